@@ -1,22 +1,44 @@
 module Kind (
   module Kind.CLI,
   module Kind.Check,
+  module Kind.Derive,
+  module Kind.Doc,
+  module Kind.Elim,
   module Kind.Env,
   module Kind.Equal,
+  module Kind.Format,
+  module Kind.JSON,
+  module Kind.LSP,
+  module Kind.Load,
+  module Kind.Manifest,
   module Kind.Parse,
+  module Kind.Positivity,
   module Kind.Reduce,
   module Kind.Show,
+  module Kind.Termination,
   module Kind.Type,
+  module Kind.Universe,
   module Kind.Util,
 ) where
 
 import Kind.CLI
 import Kind.Check
 import Kind.CompileJS
+import Kind.Derive
+import Kind.Doc
+import Kind.Elim
 import Kind.Env
 import Kind.Equal
+import Kind.Format
+import Kind.JSON
+import Kind.LSP
+import Kind.Load
+import Kind.Manifest
 import Kind.Parse
+import Kind.Positivity
 import Kind.Reduce
 import Kind.Show
+import Kind.Termination
 import Kind.Type
+import Kind.Universe
 import Kind.Util
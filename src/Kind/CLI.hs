@@ -3,31 +3,47 @@
 
 module Kind.CLI where
 
-import Control.Exception (try)
-import Control.Monad (forM, forM_, foldM)
-import Data.List (stripPrefix, isSuffixOf, nub)
+import Control.Concurrent (threadDelay, forkIO, getNumCapabilities, setNumCapabilities)
+import Control.Concurrent.MVar
+import Control.Exception (SomeException, displayException, evaluate, try)
+import Control.Monad (forM, forM_, foldM, filterM, unless, when)
+import Data.Char (isDigit, isSpace)
+import Data.Graph (SCC(..), stronglyConnComp)
+import Data.List (intercalate, isPrefixOf, isSuffixOf, nub, nubBy, sortBy)
+import Data.Ord (comparing)
+import Data.Maybe (fromMaybe, listToMaybe)
+import Data.Time.Clock (UTCTime, diffUTCTime, getCurrentTime)
+import Data.Word (Word64)
 import Highlight (highlightError)
 import Kind.Check
 import Kind.CompileJS
+import Kind.Doc
 import Kind.Env
+import Kind.Equal
+import Kind.Format
+import Kind.JSON
+import Kind.LSP
+import Kind.Load
+import Kind.Manifest
 import Kind.Parse
 import Kind.Reduce
 import Kind.Show
 import Kind.Type
+import Kind.Universe
 import Kind.Util
 import System.Console.ANSI
-import System.Directory (canonicalizePath, getCurrentDirectory, doesDirectoryExist, doesFileExist, getDirectoryContents)
-import System.Environment (getArgs)
+import System.Directory (canonicalizePath, createDirectoryIfMissing, getCurrentDirectory, getModificationTime, doesDirectoryExist, doesFileExist, getDirectoryContents, removeFile)
+import System.Environment (getArgs, lookupEnv)
 import System.Exit (exitWith, ExitCode(ExitSuccess, ExitFailure))
-import System.FilePath (takeDirectory, (</>), takeFileName, dropExtension, isExtensionOf)
-import System.IO (readFile)
+import System.FilePath (takeDirectory, (</>), takeFileName, dropExtension, isExtensionOf, splitSearchPath)
+import System.IO (readFile, readFile', hFlush, stdout, isEOF)
+import Text.Read (readMaybe)
 import qualified Data.IntMap.Strict as IM
 import qualified Data.Map.Strict as M
 import qualified Data.Set as S
 
 import Debug.Trace
 
-type FileCtx = (Book, M.Map FilePath [String], M.Map FilePath [String])
 type Command = String -> FileCtx -> String -> String -> IO (Either String ())
 
 -- main :: IO ()
@@ -35,92 +51,206 @@ type Command = String -> FileCtx -> String -> String -> IO (Either String ())
 
 main :: IO ()
 main = do
-  args     <- getArgs
+  (args, opts) <- getArgs >>= \ args -> case parseOpts args of
+    Left err -> do
+      putStrLn err
+      exitWith (ExitFailure 1)
+    Right parsed -> return parsed
   currPath <- getCurrentDirectory
-  bookPath <- findBookDir currPath
-  case bookPath of
-    Nothing -> do
-      putStrLn "Error: No 'book' directory found in the path."
+  roots <- findBookRoots opts currPath
+  case roots of
+    Left err -> do
+      putStrLn err
+      exitWith (ExitFailure 1)
+    Right [] -> do
+      putStrLn "Error: No 'kindbook' directory or 'kind.toml' manifest found in the path. Use --book <dir> or KIND_PATH to set the book roots."
       exitWith (ExitFailure 1)
-    Just bookPath -> do
+    Right roots -> do
       result <- case args of
         -- ["check"]      -> runWithAll bookPath cliCheckAll
-        ["run", arg]   -> runWithOne bookPath arg cliNormal
-        ["check"]      -> runWithAll bookPath cliCheck
-        ["check", arg] -> runWithOne bookPath arg cliCheck
-        ["to-js", arg] -> runWithOne bookPath arg cliToJS
-        ["show", arg]  -> runWithOne bookPath arg cliShow
-        ["deps", arg]  -> runWithOne bookPath arg cliDeps
-        ["rdeps", arg] -> runWithOne bookPath arg cliRDeps
+        ["run", arg]   -> runWithOne opts roots arg (cliNormal opts roots [])
+        ("run" : arg : "--" : inputs) -> runWithOne opts roots arg (cliNormal opts roots inputs)
+        ["check"] | M.member "--watch" opts -> runWithWatch opts roots Nothing
+        ["check"] | M.member "-j" opts || M.member "--stats" opts || M.member "--universes" opts -> runCheckAll opts roots
+        ["check"]      -> runWithAll opts roots (cliCheck opts roots)
+        ["check", arg] | M.member "--trace" opts -> runWithOne opts roots arg (cliTrace opts)
+        ["check", arg] | M.member "--watch" opts -> runWithWatch opts roots (Just arg)
+        ["check", arg] -> runWithOne opts roots arg (cliCheck opts roots)
+        ["to-js", arg] -> runWithOne opts roots arg cliToJS
+        ["show", arg]  -> runWithOne opts roots arg cliShow
+        ["deps", arg]  -> runWithOne opts roots arg cliDeps
+        ["rdeps", arg] -> runWithOne opts roots arg cliRDeps
+        ["graph", arg] -> runWithOne opts roots arg (cliGraph opts)
+        ["test"]       -> runTests opts roots Nothing
+        ["test", arg]  -> runTests opts roots (Just arg)
+        ["holes"]      -> runHoles opts roots Nothing
+        ["holes", arg] -> runHoles opts roots (Just arg)
+        ["search", pat] -> runSearch opts roots pat
+        ["unused"]     -> runUnused opts roots
+        ["rename", old, new] -> runRename opts roots old new
+        ["doc"]        -> cliLoadBook opts roots >>= runDoc opts roots
+        ["fmt"]        -> runFormat opts roots Nothing
+        ["fmt", arg]   -> runFormat opts roots (Just arg)
+        ["repl"]       -> runRepl roots
+        ["lsp"]        -> runLSP roots
         _              -> printHelp
       case result of
         Left err -> do
-          putStrLn err
+          if isJSON opts
+            then putStrLn $ showJSON $ JObj [("kind", JStr "failure"), ("message", JStr err)]
+            else putStrLn err
           exitWith (ExitFailure 1)
         Right _ -> do
           exitWith ExitSuccess
 
+-- Splits the command-line arguments into positional arguments and options
+parseOpts :: [String] -> Either String ([String], Opts)
+parseOpts [] = Right ([], M.empty)
+parseOpts ("--" : args) = Right ("--" : args, M.empty)
+parseOpts (opt : val : args) | opt `elem` valueOpts = do
+  checkOptValue opt val
+  (args', opts) <- parseOpts args
+  return (args', M.insert opt val opts)
+parseOpts (opt : args) | opt `elem` flagOpts = do
+  (args', opts) <- parseOpts args
+  return (args', M.insert opt "" opts)
+parseOpts (opt : _) | opt `elem` valueOpts =
+  Left $ "Error: the option '" ++ opt ++ "' takes a value. Use 'kind help' to see the options."
+parseOpts (opt : _) | "-" `isPrefixOf` opt && opt /= "-" =
+  Left $ "Error: unknown option '" ++ opt ++ "'. Use 'kind help' to see the options."
+parseOpts (arg : args) = do
+  (args', opts) <- parseOpts args
+  return (arg : args', opts)
+
+-- Options that take a value
+valueOpts :: [String]
+valueOpts = ["--format", "--book", "--level", "-j", "--roots", "--out", "--termination"]
+
+-- Options that don't
+flagOpts :: [String]
+flagOpts = ["--watch", "--stats", "--universes", "--trace", "--check"]
+
+-- Checks the value of an option that takes a number or one of some choices
+checkOptValue :: String -> String -> Either String ()
+checkOptValue "-j" val | maybe True (< 1) (readMaybe val :: Maybe Int) =
+  Left $ "Error: -j takes a positive number of threads, not '" ++ val ++ "'."
+checkOptValue "--level" val | maybe True (< 0) (readMaybe val :: Maybe Int) =
+  Left $ "Error: --level takes a non-negative number, not '" ++ val ++ "'."
+checkOptValue "--termination" val | val `notElem` ["error", "warn", "off"] =
+  Left $ "Error: --termination takes error, warn or off, not '" ++ val ++ "'."
+checkOptValue "--format" val | val `notElem` ["text", "json"] =
+  Left $ "Error: --format takes text or json, not '" ++ val ++ "'."
+checkOptValue _ _ = Right ()
+
 printHelp :: IO (Either String ())
 printHelp = do
   putStrLn "Kind usage:"
   putStrLn "  kind check             # Checks all .kind files in the current directory and subdirectories"
   putStrLn "  kind check <name|path> # Type-checks all definitions in the specified file"
-  putStrLn "  kind run   <name|path> # Normalizes the specified definition"
+  putStrLn "  kind check --watch [name|path] # Re-checks changed files and their dependents"
+  putStrLn "  kind run   <name|path> [-- args...] # Normalizes the specified definition, applied to the given terms"
   putStrLn "  kind show  <name|path> # Stringifies the specified definition"
   putStrLn "  kind to-js <name|path> # Compiles the specified definition to JavaScript"
   putStrLn "  kind deps  <name|path> # Shows immediate dependencies of the specified definition"
   putStrLn "  kind rdeps <name|path> # Shows all dependencies of the specified definition recursively"
+  putStrLn "  kind graph <name|path> # Prints the definition and file dependency graph, as DOT or JSON (--format json)"
+  putStrLn "  kind test  [name|path] # Checks the test definitions (#name) of the specified file or of the whole book"
+  putStrLn "  kind holes [name|path] # Lists the holes left, with goals and contexts, and counts unsolved metas"
+  putStrLn "  kind search <type>     # Lists the definitions whose types unify with a pattern, like '∀(A: *) (List A) -> U64'"
+  putStrLn "  kind unused [--roots a,b] # Lists definitions, files and use aliases nothing reaches from the roots (main and tests)"
+  putStrLn "  kind rename <old> <new> # Renames a definition, moving its file and updating its references"
+  putStrLn "  kind doc [--out <dir>] # Generates an HTML documentation site for the book (default: doc)"
+  putStrLn "  kind fmt   [name|path] # Normalizes the spacing and indentation (keeping line breaks) of the specified file, directory (under the book) or the whole book"
+  putStrLn "  kind fmt --check [name|path] # Fails if the spacing or indentation of any of the files isn't normalized"
+  putStrLn "  kind repl              # Starts an interactive read-eval-print loop"
+  putStrLn "  kind lsp               # Starts a Language Server Protocol server over stdio"
   putStrLn "  kind help              # Shows this help message"
+  putStrLn ""
+  putStrLn "Options:"
+  putStrLn "  --format <text|json>   # Prints checker outputs and parse errors as text, or as JSON lines (check, run; default: text)"
+  putStrLn "  --level <n>            # Sets the ref-expansion level for normalization: 0 = never, 1+ = on redexes (run; default: 2)"
+  putStrLn "  -j <n>                 # Checks independent definitions on n threads (check)"
+  putStrLn "  --termination <mode>   # Checks that recursive calls decrease: error, warn or off; #partial exempts a definition (default: error)"
+  putStrLn "  --trace                # Prints the tree of checker steps (infer, check, equal, unify, solve...) of a definition (check <name>)"
+  putStrLn "  --stats                # Prints time, equal/unify calls, reductions, solved metas and postponed checks per definition, slowest first (check)"
+  putStrLn "  --universes            # Checks with stratified universes, *0 : *1 : ..., inferring one level per * of the book, shared by all uses (no universe polymorphism) (check)"
+  putStrLn "  --book <dir[:dir...]>  # Sets the book roots, searched in order (default: from the nearest 'kind.toml', or the nearest 'kindbook')"
+  putStrLn ""
+  putStrLn "Environment:"
+  putStrLn "  KIND_PATH=<dir[:dir...]> # Extra book roots, searched after the main ones (e.g. vendored books)"
   return $ Right ()
 
 -- CLI Commands
 -- ------------
 
--- Normalizes the target definition
-cliNormal :: Command
-cliNormal bookPath (book, _, _) defName defPath =
-  case M.lookup "main" book of
+-- Normalizes the target definition, applied to the given arguments
+cliNormal :: Opts -> Roots -> [String] -> Command
+cliNormal opts roots inputs bookPath (book, _, _, _) defName defPath =
+  case M.lookup defName book of
     Just term -> do
-      result <- showInfo book IM.empty (Print term 0)
-      putStrLn result
-      return $ Right ()
+      args <- forM inputs (doParseTerm "<args>")
+      if any isBadParse args
+        then return $ Left $ "Error: Could not parse the arguments of '" ++ defName ++ "'."
+        else do
+          book' <- foldM (\ book dep -> (\ (book', _, _, _) -> book') <$> cliLoadName opts roots book dep) book (concatMap getDeps args)
+          let level  = maybe 2 id (M.lookup "--level" opts >>= readMaybe)
+          -- Applies the body, as `normal` doesn't unfold a bare `Ref`
+          let result = showTermGo True (normal book' IM.empty level (foldl App term args) 0) 0
+          if isJSON opts
+            then putStrLn $ showJSON $ JObj [("kind", JStr "print"), ("value", JStr result)]
+            else putStrLn result
+          return $ Right ()
     Nothing -> do
       return $ Left $ "Error: Definition '" ++ defName ++ "' not found."
+  where
+    isBadParse (Ref "bad-parse") = True
+    isBadParse _                 = False
 
 -- Checks all definitions in the target file
-cliCheck :: Command
-cliCheck bookPath (book, defs, _) defName defPath = do
+cliCheck :: Opts -> Roots -> Command
+cliCheck opts roots bookPath ctx@(book, defs, _, partials) defName defPath = do
   case M.lookup defPath defs of
-    Just fileDefNames -> do
-      results <- forM fileDefNames $ \fileDefName -> do
-        case M.lookup fileDefName book of
-          Just term -> do
-            case envRun (doCheck term) book of
-              Done state _ -> do
-                cliPrintLogs state
-                cliPrintWarn term state
-                putStrLn $ "\x1b[32m✓ " ++ fileDefName ++ "\x1b[0m"
-                return $ Right ()
-              Fail state -> do
-                cliPrintLogs state
-                cliPrintWarn term state
-                putStrLn $ "\x1b[31m✗ " ++ fileDefName ++ "\x1b[0m"
-                return $ Left $ "Error."
-          Nothing -> return $ Left $ "Definition not found: " ++ fileDefName
-      putStrLn ""
-      return $ sequence_ results
+    Just _ -> do
+      let fileDefNames = fileChecks ctx defPath
+      cache <- if M.member "--universes" opts then return M.empty else loadCache bookPath
+      checks <- checkDefs opts partials book (statsCache opts cache) (levelDeps opts book fileDefNames)
+      (cache', results) <- printChecks opts book (cache, []) [(name, var) | (name, var) <- checks, name `elem` fileDefNames]
+      unless (M.member "--universes" opts) $ saveCache bookPath cache'
+      levels <- checkLevels opts book checks
+      unless (isJSON opts) $ putStrLn ""
+      when (M.member "--stats" opts) $ printStats opts checks
+      return $ sequence_ (results ++ [levels])
     Nothing -> do
       return $ Left $ "No definitions found in file: " ++ defPath
 
+-- Checks a definition, recording the steps of the checker, and prints them
+-- as a tree (indented text, or JSON), followed by the result
+cliTrace :: Opts -> Command
+cliTrace opts bookPath (book, _, _, _) defName _ = do
+  case M.lookup defName book of
+    Just term -> do
+      let (state, ok) = case envRunTrace (doCheck term) book M.empty of
+            Done state _ -> (state, True)
+            Fail state   -> (state, False)
+      let State _ _ _ _ _ _ events _ = state
+      let steps = traceTree (reverse (fromMaybe [] events))
+      if isJSON opts
+        then putStrLn $ showJSON $ JObj [("kind", JStr "trace"), ("name", JStr defName), ("steps", JArr (map traceJSON steps))]
+        else mapM_ (putStr . showTrace 0) steps
+      cliPrintCheck opts defName term state ok
+      return $ if ok then Right () else Left "Error."
+    Nothing -> do
+      return $ Left $ "Error: Definition '" ++ defName ++ "' not found."
+
 -- Compiles the whole book to JS
 cliToJS :: Command
-cliToJS bookPath (book, _, _) _ _ = do
+cliToJS bookPath (book, _, _, _) _ _ = do
   putStrLn $ compileJS book
   return $ Right ()
 
 -- Shows a definition
 cliShow :: Command
-cliShow bookPath (book, _, _) defName _ = 
+cliShow bookPath (book, _, _, _) defName _ = 
   case M.lookup defName book of
     Just term -> do
       putStrLn $ showTerm term
@@ -130,7 +260,7 @@ cliShow bookPath (book, _, _) defName _ =
 
 -- Shows immediate dependencies of a definition
 cliDeps :: Command
-cliDeps bookPath (book, _, _) defName _ = 
+cliDeps bookPath (book, _, _, _) defName _ = 
   case M.lookup defName book of
     Just term -> do
       forM_ (filter (/= defName) $ nub $ getDeps term) $ \dep -> putStrLn dep
@@ -140,71 +270,787 @@ cliDeps bookPath (book, _, _) defName _ =
 
 -- Shows all dependencies of a definition recursively
 cliRDeps :: Command
-cliRDeps bookPath (book, _, _) defName _ = do
+cliRDeps bookPath (book, _, _, _) defName _ = do
   let deps = S.toList $ S.delete defName $ getAllDeps book defName
   forM_ deps $ \dep -> putStrLn dep
   return $ Right ()
 
+-- Prints the definition- and file-level dependency graph of a definition,
+-- in DOT (default) or JSON. Edges inside a dependency cycle are highlighted.
+cliGraph :: Opts -> Command
+cliGraph opts bookPath (book, defs, _, _) defName _ = do
+  let names    = S.toList (S.filter (`M.member` book) (getAllDeps book defName))
+  let fileOf   = M.fromList [(name, file) | (file, fileNames) <- M.toList defs, name <- fileNames]
+  let defEdges = nub [(name, dep) | name <- names, dep <- getDeps (book M.! name), dep /= name, M.member dep book]
+  let files    = nub [file | name <- names, Just file <- [M.lookup name fileOf]]
+  let fileEdges = nub [(fa, fb) | (a, b) <- defEdges, Just fa <- [M.lookup a fileOf], Just fb <- [M.lookup b fileOf], fa /= fb]
+  let defCycles  = graphCycles names defEdges
+  let fileCycles = graphCycles files fileEdges
+  if isJSON opts
+    then putStrLn $ showJSON $ JObj
+      [ ("kind", JStr "graph")
+      , ("definitions", graphJSON names defEdges defCycles)
+      , ("files", graphJSON files fileEdges fileCycles)
+      ]
+    else do
+      putStrLn "digraph kind {"
+      putStrLn "  subgraph cluster_definitions {"
+      putStrLn "    label = \"definitions\";"
+      graphDot "    " "" names defEdges defCycles
+      putStrLn "  }"
+      putStrLn "  subgraph cluster_files {"
+      putStrLn "    label = \"files\";"
+      putStrLn "    node [shape = box];"
+      graphDot "    " "file:" files fileEdges fileCycles
+      putStrLn "  }"
+      putStrLn "}"
+  return $ Right ()
+
+-- Groups the nodes of a graph that are in a dependency cycle
+graphCycles :: [String] -> [(String, String)] -> [[String]]
+graphCycles nodes edges = [ns | CyclicSCC ns <- stronglyConnComp [(n, n, [b | (a, b) <- edges, a == n]) | n <- nodes]]
+
+-- Checks if an edge is inside one of the cycles
+inCycle :: [[String]] -> (String, String) -> Bool
+inCycle cycles (a, b) = any (\ns -> a `elem` ns && b `elem` ns) cycles
+
+graphDot :: String -> String -> [String] -> [(String, String)] -> [[String]] -> IO ()
+graphDot indent prefix nodes edges cycles = do
+  forM_ nodes $ \node -> do
+    let color = if any (node `elem`) cycles then " [color = red]" else ""
+    putStrLn $ indent ++ showJSONStr (prefix ++ node) ++ color ++ ";"
+  forM_ edges $ \edge@(a, b) -> do
+    let color = if inCycle cycles edge then " [color = red]" else ""
+    putStrLn $ indent ++ showJSONStr (prefix ++ a) ++ " -> " ++ showJSONStr (prefix ++ b) ++ color ++ ";"
+
+graphJSON :: [String] -> [(String, String)] -> [[String]] -> JSON
+graphJSON nodes edges cycles = JObj
+  [ ("nodes", JArr (map JStr nodes))
+  , ("edges", JArr [JObj [("from", JStr a), ("to", JStr b), ("cycle", JBool (inCycle cycles (a, b)))] | (a, b) <- edges])
+  , ("cycles", JArr (map (JArr . map JStr) cycles))
+  ]
+
 -- CLI Runners
 -- -----------
 
 -- Runs a command on a single file
-runWithOne :: FilePath -> String -> Command -> IO (Either String ())
-runWithOne bookPath arg action = do
-  let defName = getDefName bookPath arg
-  let defPath = getDefPath bookPath defName
-  cliCtx <- loadName bookPath M.empty defName
+runWithOne :: Opts -> Roots -> String -> Command -> IO (Either String ())
+runWithOne opts roots arg action = do
+  let bookPath = snd (head roots)
+  let defName  = getDefName roots arg
+  defPath <- fromMaybe (getDefPath bookPath defName) <$> findDefFile roots defName
+  cliCtx <- cliLoadName opts roots M.empty defName
   action bookPath cliCtx defName defPath
 
--- Runs a command on all files
-runWithAll :: FilePath -> Command -> IO (Either String ())
-runWithAll bookPath action = do
+-- Checks all files of the main book root at once, so that definitions from
+-- different files can be checked in parallel; prints in the usual order
+runCheckAll :: Opts -> Roots -> IO (Either String ())
+runCheckAll opts roots = do
+  let bookPath = snd (head roots)
   files <- findKindFiles bookPath
+  ctx@(book, _, _, partials) <- cliLoadBook opts roots
+  cache <- if M.member "--universes" opts then return M.empty else loadCache bookPath
+  let fileDefNames = [(file, fileChecks ctx file) | file <- files]
+  checks <- checkDefs opts partials book (statsCache opts cache) (levelDeps opts book (nub (concatMap snd fileDefNames)))
+  let checkOf = M.fromList checks
+  (cache', results) <- foldM (\ (cache, results) (file, names) -> do
+      unless (isJSON opts) $ putStrLn $ "\x1b[1m\x1b[4m[" ++ file ++ "]\x1b[0m"
+      (cache', results') <- if null names
+        then return (cache, [Left $ "No definitions found in file: " ++ file])
+        else printChecks opts book (cache, []) [(name, checkOf M.! name) | name <- names]
+      unless (isJSON opts || null names) $ putStrLn ""
+      return (cache', results ++ results')
+    ) (cache, []) fileDefNames
+  unless (M.member "--universes" opts) $ saveCache bookPath cache'
+  levels <- checkLevels opts book checks
+  when (M.member "--stats" opts) $ printStats opts checks
+  return $ sequence_ (results ++ [levels])
+
+-- Runs a command on all files of the main book root
+runWithAll :: Opts -> Roots -> Command -> IO (Either String ())
+runWithAll opts roots action = do
+  files <- findKindFiles (snd (head roots))
   results <- forM files $ \file -> do
-    putStrLn $ "\x1b[1m\x1b[4m[" ++ file ++ "]\x1b[0m"
-    runWithOne bookPath file action
+    unless (isJSON opts) $ putStrLn $ "\x1b[1m\x1b[4m[" ++ file ++ "]\x1b[0m"
+    runWithOne opts roots file action
   return $ sequence_ results
 
--- Loader
--- ------
+-- Watches the book roots (the main one, the dependencies' and KIND_PATH's),
+-- re-checking changed files and the files that depend on them
+runWithWatch :: Opts -> Roots -> Maybe String -> IO (Either String ())
+runWithWatch opts roots target = do
+  targetPath <- forM target $ \arg -> do
+    let defName = getDefName roots arg
+    fromMaybe (getDefPath bookPath defName) <$> findDefFile roots defName
+  let only files = maybe files (\path -> filter (== path) files) targetPath
+  files <- findKindFiles bookPath
+  forM_ (only files) recheck
+  stamps  <- getStamps (map snd roots)
+  fileCtx <- cliLoadBook opts roots
+  putStrLn $ "\x1b[2mWatching " ++ intercalate ", " (map snd roots) ++ " for changes...\x1b[0m"
+  loop only stamps fileCtx
+  where
+    recheck file = do
+      unless (isJSON opts) $ putStrLn $ "\x1b[1m\x1b[4m[" ++ file ++ "]\x1b[0m"
+      runWithOne opts roots file (cliCheck opts roots)
+    -- Files that changed, appeared or disappeared are affected, along with
+    -- the files that depended on them before the change, or do after it
+    loop only stamps fileCtx = do
+      threadDelay 500000
+      stamps' <- getStamps (map snd roots)
+      let changed = [file | (file, time) <- M.toList stamps', M.lookup file stamps /= Just time]
+      let deleted = [file | file <- M.keys stamps, not (M.member file stamps')]
+      if null changed && null deleted
+        then loop only stamps' fileCtx
+        else do
+          fileCtx' <- cliLoadBook opts roots
+          let rdeps    = concat [getFileRDeps ctx file | ctx <- [fileCtx, fileCtx'], file <- changed ++ deleted]
+          let affected = nub [file | file <- changed ++ rdeps, M.member file stamps']
+          forM_ (only affected) recheck
+          loop only stamps' fileCtx'
+    bookPath = snd (head roots)
+
+-- Loads a file, or the whole main book root, returning the files loaded
+loadTarget :: Opts -> Roots -> Maybe String -> IO ([FilePath], FileCtx)
+loadTarget opts roots target = case target of
+  Nothing -> do
+    files <- findKindFiles (snd (head roots))
+    fileCtx <- cliLoadBook opts roots
+    return (files, fileCtx)
+  Just arg -> do
+    let defName = getDefName roots arg
+    defPath <- fromMaybe (getDefPath (snd (head roots)) defName) <$> findDefFile roots defName
+    fileCtx <- cliLoadName opts roots M.empty defName
+    return ([defPath], fileCtx)
+
+-- Loads a name and its dependencies, printing the files that failed to parse
+cliLoadName :: Opts -> Roots -> Book -> String -> IO FileCtx
+cliLoadName opts roots book name = loadName roots book name >>= cliPrintFails opts
+
+-- Loads every file in the main book root, printing those that failed to parse
+cliLoadBook :: Opts -> Roots -> IO FileCtx
+cliLoadBook opts roots = loadBook roots >>= cliPrintFails opts
+
+-- Prints the parse errors of a load, as text or JSON
+cliPrintFails :: Opts -> (FileCtx, [ParseFail]) -> IO FileCtx
+cliPrintFails opts (fileCtx, fails) = do
+  forM_ fails $ \ (file, code, err) -> do
+    if isJSON opts
+      then showParseErrorJSON file err
+      else showParseError file code err
+  return fileCtx
+
+-- Checks a file, or the whole main book root, and lists every hole left,
+-- with its location, goal and context, plus the count of unsolved metas
+runHoles :: Opts -> Roots -> Maybe String -> IO (Either String ())
+runHoles opts roots target = do
+  (files, (book, defs, _, partials)) <- loadTarget opts roots target
+  let names = nub [name | file <- files, name <- M.findWithDefault [] file defs]
+  checks <- checkDefs opts partials book M.empty names
+  counts <- forM checks $ \ (name, var) -> do
+    outcome <- readMVar var
+    case outcome of
+      Checked term result _ -> do
+        let State _ fill _ logs _ _ _ _ = case result of
+              Done state _ -> state
+              Fail state   -> state
+        -- A hole can be logged more than once; distinct holes can share a name
+        let found = nubBy (\ a b -> holeKey a == holeKey b) [info | info@(Found _ _ _ _ _) <- reverse logs]
+        let metas = max 0 (countMetas term - IM.size fill)
+        forM_ found $ \info -> do
+          if isJSON opts
+            then putStrLn (showInfoJSON book fill info)
+            else do
+              let loc = case info of
+                    Found (Just (Cod (Loc file lin col) _)) _ _ _ _ -> file ++ ":" ++ show lin ++ ":" ++ show col
+                    _                                               -> "unknown location"
+              putStrLn $ "\x1b[4m" ++ loc ++ "\x1b[0m \x1b[2m(" ++ name ++ ")\x1b[0m"
+              showInfo book fill info >>= putStr
+        return (length found, metas)
+      _ -> return (0, 0)
+  let holes = sum (map fst counts)
+  let metas = sum (map snd counts)
+  if isJSON opts
+    then putStrLn $ showJSON $ JObj [("kind", JStr "holes"), ("holes", JNum (fromIntegral holes)), ("unsolved", JNum (fromIntegral metas))]
+    else putStrLn $ show holes ++ " holes, " ++ show metas ++ " unsolved metas"
+  return $ Right ()
+  where
+    holeKey (Found src nam _ _ _) = (fmap (\ (Cod (Loc file iniLin iniCol) (Loc _ endLin endCol)) -> (file, iniLin, iniCol, endLin, endCol)) src, nam)
+    holeKey _                     = (Nothing, "")
 
--- Loads a name and all its dependencies recursively
-loadName :: FilePath -> Book -> String -> IO FileCtx
-loadName bookPath book name = do
-  if M.member name book
-    then do
-      return (book, M.empty, M.empty)
+-- Lists the definitions of the book whose types unify with a type pattern.
+-- Each candidate is tried in its own `Env`, with the pattern's metas
+-- numbered after the candidate's own, so they can't clash.
+runSearch :: Opts -> Roots -> String -> IO (Either String ())
+runSearch opts roots code = do
+  pattern <- doParseTerm "<pattern>" code
+  case pattern of
+    Ref "bad-parse" -> return $ Left "Error: Invalid type pattern."
+    _ -> do
+      (bookAll, _, _, _) <- cliLoadBook opts roots
+      (book, _, _, _) <- foldM (\ (acc, _, _, _) dep -> cliLoadName opts roots acc dep) (bookAll, M.empty, M.empty, S.empty) (getDeps pattern)
+      let found = [(name, typ) | (name, term) <- M.toList book, Just typ <- [defType term], matches book term typ pattern]
+      forM_ found $ \ (name, typ) -> do
+        if isJSON opts
+          then putStrLn $ showJSON $ JObj [("kind", JStr "search"), ("name", JStr name), ("type", JStr (showTerm typ))]
+          else putStrLn $ "\x1b[1m" ++ name ++ "\x1b[0m : " ++ showTerm typ
+      when (null found && not (isJSON opts)) $ do
+        putStrLn "No matches."
+      return $ Right ()
+  where
+    defType (Src _ val)     = defType val
+    defType (Ann _ _ typ)   = Just typ
+    defType _               = Nothing
+    matches book term typ pattern =
+      let pattern' = bind (fst (genMetasGo pattern (countMetas term))) [] in
+      case envRun (equal pattern' typ 0) book of
+        Done _ True -> True
+        _           -> False
+
+-- Type-checks the test definitions (`#name`, numbered `name#0`, `name#1`...)
+-- of a file, or of the whole main book root, and counts passes and failures
+runTests :: Opts -> Roots -> Maybe String -> IO (Either String ())
+runTests opts roots target = do
+  (files, (book, defs, _, partials)) <- loadTarget opts roots target
+  let tests = [name | file <- files, name <- M.findWithDefault [] file defs, isTestName name]
+  checks <- checkDefs opts partials book M.empty tests
+  passes <- forM checks $ \ (name, var) -> do
+    outcome <- readMVar var
+    let state = case outcome of
+          Checked _ (Done state _) _ -> Just state
+          Checked _ (Fail state) _   -> Just state
+          _                        -> Nothing
+    let passed = case outcome of
+          Checked _ (Done (State _ _ _ logs _ _ _ _) _) _ -> not (any isError logs)
+          _                                       -> False
+    let src = listToMaybe [cod | (cod, _, _) <- getSrcs (book M.! name) 0]
+    if isJSON opts
+      then do
+        unless passed $ mapM_ cliPrintLogsJSON state
+        putStrLn $ showJSON $ JObj $
+          [ ("kind", JStr "test"), ("name", JStr name), ("status", JStr (if passed then "pass" else "fail")) ] ++
+          case src of
+            Just cod@(Cod (Loc file _ _) _) -> [("file", JStr file), ("range", codToJSON cod)]
+            Nothing                         -> [("file", JNull), ("range", JNull)]
+      else do
+        unless passed $ mapM_ cliPrintLogs state
+        let loc = case src of
+              Just (Cod (Loc file lin col) _) -> " \x1b[2m" ++ file ++ ":" ++ show lin ++ ":" ++ show col ++ "\x1b[0m"
+              Nothing                         -> ""
+        if passed
+          then putStrLn $ "\x1b[32m✓ " ++ name ++ "\x1b[0m" ++ loc
+          else putStrLn $ "\x1b[31m✗ " ++ name ++ "\x1b[0m" ++ loc
+    return passed
+  let passed = length (filter id passes)
+  let failed = length (filter not passes)
+  if isJSON opts
+    then putStrLn $ showJSON $ JObj [("kind", JStr "tests"), ("passed", JNum (fromIntegral passed)), ("failed", JNum (fromIntegral failed))]
+    else putStrLn $ "\n" ++ show passed ++ " passed, " ++ show failed ++ " failed"
+  if failed > 0
+    then return $ Left $ "Error: " ++ show failed ++ " test(s) failed."
+    else return $ Right ()
+  where
+    isError (Error _ _ _ _ _) = True
+    isError _                 = False
+
+-- Reports the definitions and files of the main book root that aren't
+-- reachable from the roots (`--roots a,b`; by default `main` and the tests),
+-- and the `use` aliases never expanded in their files
+runUnused :: Opts -> Roots -> IO (Either String ())
+runUnused opts roots = do
+  files <- findKindFiles (snd (head roots))
+  (book, defs, _, _) <- cliLoadBook opts roots
+  let names = [name | file <- files, name <- M.findWithDefault [] file defs]
+  let start = case M.lookup "--roots" opts of
+        Just str -> splitCommas str
+        Nothing  -> "main" : filter isTestName names
+  let reached = S.unions (map (getAllDeps book) start)
+  let unusedDefs  = [(name, file) | file <- files, name <- M.findWithDefault [] file defs, not (S.member name reached)]
+  let unusedFiles = [file | file <- files, not (any (`S.member` reached) (M.findWithDefault [] file defs))]
+  unusedUses <- fmap concat $ forM files $ \file -> do
+    code <- readFile' file
+    return [(file, long, short) | (long, short) <- unusedAliases code]
+  if isJSON opts
+    then putStrLn $ showJSON $ JObj
+      [ ("kind", JStr "unused")
+      , ("definitions", JArr [JObj [("name", JStr name), ("file", JStr file)] | (name, file) <- unusedDefs])
+      , ("files", JArr (map JStr unusedFiles))
+      , ("aliases", JArr [JObj [("file", JStr file), ("name", JStr long), ("alias", JStr short)] | (file, long, short) <- unusedUses])
+      ]
     else do
-      let dirPath = bookPath </> name
+      putStrLn $ "\x1b[1mUnused definitions:\x1b[0m " ++ show (length unusedDefs)
+      forM_ unusedDefs $ \ (name, file) -> putStrLn $ "- " ++ name ++ " \x1b[2m" ++ file ++ "\x1b[0m"
+      putStrLn $ "\x1b[1mUnused files:\x1b[0m " ++ show (length unusedFiles)
+      forM_ unusedFiles $ \file -> putStrLn $ "- " ++ file
+      putStrLn $ "\x1b[1mUnused aliases:\x1b[0m " ++ show (length unusedUses)
+      forM_ unusedUses $ \ (file, long, short) -> putStrLn $ "- use " ++ long ++ " as " ++ short ++ " \x1b[2m" ++ file ++ "\x1b[0m"
+  return $ Right ()
+  where
+    splitCommas str = case break (== ',') str of
+      (name, [])       -> [name]
+      (name, _ : rest) -> name : splitCommas rest
+
+-- Finds the `use long as short` aliases of a file that no name in it expands
+unusedAliases :: String -> [(String, String)]
+unusedAliases code = [(long, short) | (long, short) <- uses, not (any (expands short) body)] where
+  (uses, body) = sourceUses code
+  expands short name = name == short || (short ++ "/") `isPrefixOf` name
+
+-- Renames a definition, moving its file to the path of the new name, and
+-- rewrites its references in every file of the main book root. Names defined
+-- in the same file under the old name (like `Old/helper`) move along.
+runRename :: Opts -> Roots -> String -> String -> IO (Either String ())
+runRename opts roots old new = do
+  let bookPath = snd (head roots)
+  ctx@(book, defs, _, _) <- cliLoadBook opts roots
+  oldPath <- findDefFile roots old
+  case oldPath of
+    _ | M.member new book -> return $ Left $ "Error: Definition '" ++ new ++ "' already exists."
+    Nothing -> return $ Left $ "Error: Definition '" ++ old ++ "' not found."
+    Just oldPath | not (M.member oldPath defs) -> return $ Left $ "Error: Definition '" ++ old ++ "' is outside of the book."
+    Just oldPath -> do
+      -- Also moves the generated eliminators, as other files may refer to them
+      let moved = [name | name <- fileChecks ctx oldPath, name == old || (old ++ "/") `isPrefixOf` name]
+      -- Keeps the file layout: `Old.kind` becomes `New.kind`, and `Old/Old.kind`
+      -- becomes `New/New.kind` (also when a `New` directory already exists,
+      -- as `findDefFile` looks there first); other files keep their path
+      newDir <- doesDirectoryExist (bookPath </> new)
+      let dirPath name = bookPath </> name </> takeFileName name ++ ".kind"
+      let newPath
+            | oldPath == dirPath old                     = dirPath new
+            | oldPath == getDefPath bookPath old, newDir = dirPath new
+            | oldPath == getDefPath bookPath old         = getDefPath bookPath new
+            | otherwise                                  = oldPath
+      files <- findKindFiles bookPath
+      sources <- forM files $ \file -> do
+        code <- readFile' file
+        return (file, code, runParseBook file code)
+      case [file | (file, _, Left _) <- sources] of
+        (file : _) -> return $ Left $ "Error: Could not parse '" ++ file ++ "', so nothing was renamed."
+        [] -> do
+          forM_ sources $ \ (file, code, parsed) -> do
+            let refs  = S.fromList [(lin, col) | Right (book, _, _) <- [parsed], term <- M.elems book, (Cod (Loc _ lin col) _, Ref _, _) <- getSrcs term 0]
+            let code' = renameSource refs moved old new code
+            let target = if file == oldPath then newPath else file
+            when (code' /= code || target /= file) $ do
+              createDirectoryIfMissing True (takeDirectory target)
+              writeFile target code'
+              when (target /= file) $ removeFile file
+              if isJSON opts
+                then putStrLn $ showJSON $ JObj [("kind", JStr "rename"), ("file", JStr target)]
+                else putStrLn $ "\x1b[32m✓ " ++ target ++ " (renamed)\x1b[0m"
+          return $ Right ()
+
+-- Rewrites the references to the moved names in a source file, as written.
+-- Only definition heads (at the start of a line, or after `data` and
+-- `#partial`), `use` targets, and the references the parser found (given by
+-- their starting line and column) are renamed, so that local binders and
+-- fields with the same name are left alone. Names are matched after expanding
+-- the file's `use` aliases, and are kept short while an alias still covers
+-- their new name.
+renameSource :: S.Set (Int, Int) -> [String] -> String -> String -> String -> String
+renameSource refs moved old new code = go "" (1, 1) code where
+  uses = [(short, long) | (long, short) <- fst (sourceUses code)]
+  rename name = if name `elem` moved then new ++ drop (length old) name else name
+  go prev pos ""                             = ""
+  go prev pos str@('/' : '/' : _)            = let (com, rest) = break (== '\n') str in com ++ go prev (advance pos com) rest
+  go prev pos str@('"' : _)                  = let (txt, rest) = formatLexText '"' str in txt ++ go prev (advance pos txt) rest
+  go prev pos str@('\'' : _)                 = let (txt, rest) = formatLexText '\'' str in txt ++ go prev (advance pos txt) rest
+  go prev pos str@(c : _) | formatNameChar c = let (wrd, rest) = span formatNameChar str in renameAt prev pos wrd ++ go wrd (advance pos wrd) rest
+  go prev pos (c : cs)                       = c : go (if isSpace c then prev else "") (advance pos [c]) cs
+  renameAt prev pos@(_, col) wrd
+    | col == 1 || prev `elem` ["use", "data", "partial"] || S.member pos refs = renameWord prev wrd
+    | otherwise = wrd
+  -- Moves a position past some text, counting columns like Parsec does
+  advance = foldl step where
+    step (lin, _)   '\n' = (lin + 1, 1)
+    step (lin, col) '\t' = (lin, col + 8 - (col - 1) `mod` 8)
+    step (lin, col) _    = (lin, col + 1)
+  renameWord "use" wrd = rename wrd
+  renameWord "as"  wrd = wrd
+  renameWord _     wrd = case [(short, long) | (short, long) <- uses, short == wrd || (short ++ "/") `isPrefixOf` wrd] of
+    [] -> rename wrd
+    ((short, long) : _) ->
+      let target = rename (long ++ drop (length short) wrd)
+          long'  = rename long
+      in if target == long' then short
+         else if (long' ++ "/") `isPrefixOf` target then short ++ drop (length long') target
+         else target
+
+-- Normalizes the whitespace of files in place, or just reports the files that
+-- aren't normalized with `--check`
+runFormat :: Opts -> Roots -> Maybe String -> IO (Either String ())
+runFormat opts roots target = do
+  let bookPath = snd (head roots)
+  files <- case target of
+    Nothing  -> findKindFiles bookPath
+    Just arg -> do
+      let dirPath = bookPath </> arg
       isDir <- doesDirectoryExist dirPath
       if isDir
-        then loadFile bookPath book (dirPath </> takeFileName name ++ ".kind")
-        else loadFile bookPath book (bookPath </> name ++ ".kind")
-
--- Loads a file and all its dependencies recursivelly
-loadFile :: FilePath -> Book -> FilePath -> IO FileCtx
-loadFile bookPath book filePath = do
-  fileExists <- doesFileExist filePath
-  if not fileExists
-    then do
-      return (book, M.empty, M.empty)
+        then findKindFiles dirPath
+        else do
+          let defName = getDefName roots arg
+          (: []) . fromMaybe (getDefPath bookPath defName) <$> findDefFile roots defName
+  results <- forM files $ \file -> do
+    exists <- doesFileExist file
+    if not exists then return $ Left $ "Error: File '" ++ file ++ "' not found." else do
+      code <- readFile' file
+      case runParseBook file code of
+        Left err -> do
+          showParseError file code err
+          return $ Left $ "Error: Could not format '" ++ file ++ "'."
+        Right _ | isFormatted code -> do
+          return $ Right ()
+        Right _ | M.member "--check" opts -> do
+          putStrLn $ "\x1b[31m✗ " ++ file ++ "\x1b[0m \x1b[2m(not formatted)\x1b[0m"
+          return $ Left $ "Error: Some files are not formatted."
+        Right (book, _, _) -> do
+          -- Only writes the formatted text if it parses to the same book
+          let code' = formatSource code
+          case runParseBook file code' of
+            Right (book', _, _) | M.map showTerm book' == M.map showTerm book -> do
+              writeFile file code'
+              putStrLn $ "\x1b[32m✓ " ++ file ++ "\x1b[0m \x1b[2m(formatted)\x1b[0m"
+              return $ Right ()
+            _ -> do
+              putStrLn $ "\x1b[31m✗ " ++ file ++ "\x1b[0m \x1b[2m(formatting would change its meaning; left as is)\x1b[0m"
+              return $ Left $ "Error: Could not format '" ++ file ++ "'."
+  return $ case [err | Left err <- results] of
+    []        -> Right ()
+    (err : _) -> Left err
+
+-- REPL
+-- ----
+
+-- Runs an interactive read-eval-print loop over the book
+runRepl :: Roots -> IO (Either String ())
+runRepl roots = do
+  putStrLn "Kind REPL. Type :help for a list of commands."
+  loop [] (M.empty, M.empty, M.empty, S.empty)
+  where
+    loop names ctx = do
+      putStr "λ> "
+      hFlush stdout
+      eof <- isEOF
+      if eof then return $ Right () else do
+        line <- getLine
+        case words line of
+          []             -> loop names ctx
+          [":q"]         -> return $ Right ()
+          [":quit"]      -> return $ Right ()
+          [":help"]      -> replHelp >> loop names ctx
+          [":load", arg] -> do
+            let name = getDefName roots arg
+            ctx' <- replLoad ctx name
+            loop (nub (names ++ [name])) ctx'
+          [":reload"]    -> do
+            ctx' <- foldM replLoad (M.empty, M.empty, M.empty, S.empty) names
+            loop names ctx'
+          [":show", arg] -> replCommand ctx arg cliShow >>= loop names
+          [":deps", arg] -> replCommand ctx arg cliDeps >>= loop names
+          [":rdeps", arg] -> replCommand ctx arg cliRDeps >>= loop names
+          (":t" : _)     -> replTerm ctx True (dropCommand line) >>= loop names
+          (":type" : _)  -> replTerm ctx True (dropCommand line) >>= loop names
+          ((':' : cmd) : _) -> do
+            putStrLn $ "Unknown command: :" ++ cmd
+            loop names ctx
+          _              -> replTerm ctx False line >>= loop names
+
+    -- Drops the leading ':command' word from a line
+    dropCommand line = dropWhile (/= ' ') (dropWhile (== ' ') line)
+
+    -- Loads a name, and its dependencies, into the REPL context
+    replLoad (book, defs, deps, part) name = do
+      (book', defs', deps', part') <- cliLoadName M.empty roots book name
+      return (book', M.union defs defs', M.union deps deps', S.union part part')
+
+    -- Runs a CLI command on a definition
+    replCommand ctx arg action = do
+      let name = getDefName roots arg
+      ctx' <- replLoad ctx name
+      path <- fromMaybe (getDefPath (snd (head roots)) name) <$> findDefFile roots name
+      result <- action (snd (head roots)) ctx' name path
+      case result of
+        Left err -> putStrLn err
+        Right _  -> return ()
+      return ctx'
+
+    -- Checks a term, printing its goals and either its type or its normal form
+    replTerm ctx typed code = do
+      term <- doParseTerm "<repl>" code
+      case term of
+        Ref "bad-parse" -> return ctx
+        _ -> do
+          ctx'@(book, _, _, _) <- foldM replLoad ctx (getDeps term)
+          case envRun (doCheck term) book of
+            Done state@(State _ fill _ logs _ _ _ _) termA -> do
+              if typed
+                then do
+                  cliPrintLogs state
+                  putStrLn $ showTermGo True (normal book fill 0 (getType termA) 0) 0
+                else do
+                  cliPrintLogs (State book fill [] [log | log@(Found _ _ _ _ _) <- logs] M.empty (Stats 0 0 0 0) Nothing Nothing)
+                  showInfo book fill (Print term 0) >>= putStrLn
+            Fail state -> do
+              cliPrintLogs state
+          return ctx'
+
+replHelp :: IO ()
+replHelp = do
+  putStrLn "REPL commands:"
+  putStrLn "  <term>               # Normalizes a term, showing the goals of its holes"
+  putStrLn "  :t <term>            # Infers the type of a term"
+  putStrLn "  :load <name|path>    # Loads a definition and its dependencies"
+  putStrLn "  :reload              # Reloads all loaded definitions from disk"
+  putStrLn "  :show <name|path>    # Stringifies the specified definition"
+  putStrLn "  :deps <name|path>    # Shows immediate dependencies of the specified definition"
+  putStrLn "  :rdeps <name|path>   # Shows all dependencies of the specified definition recursively"
+  putStrLn "  :q                   # Quits the REPL"
+
+-- Parallel Checking
+-- -----------------
+
+-- Outcome of checking a definition: cached, checked (with its term, final
+-- state and wall time in seconds), or not found in the book
+data Outcome = Cached | Checked Term (Res Term) Double | Crashed String | Missing
+
+-- Gets the number of checker threads, from `-j`
+getJobs :: Opts -> Int
+getJobs opts = fromMaybe 1 (M.lookup "-j" opts >>= readMaybe)
+
+-- Checks definitions on a pool of threads, taking them in topological order,
+-- so that each one can reuse the types inferred for its dependencies. Returns,
+-- in the given order, a variable that is filled with each outcome. Partial
+-- definitions skip the termination check, and are never taken from the cache.
+checkDefs :: Opts -> S.Set String -> Book -> Cache -> [String] -> IO [(String, MVar Outcome)]
+checkDefs opts partials book cache names = do
+  let jobs = getJobs opts
+  caps <- getNumCapabilities
+  when (jobs > caps) $ setNumCapabilities jobs
+  vars <- forM names $ \name -> (,) name <$> newEmptyMVar
+  let varOf = M.fromList vars
+  -- Definitions that (indirectly) refer to missing names can't be sorted; they go last
+  let bound = M.filterWithKey (\name _ -> all (`M.member` book) (getAllDeps book name)) book
+  let found = [name | (name, _) <- vars, M.member name book]
+  let order = nub ([name | (name, _) <- topoSortBook bound, M.member name varOf] ++ found)
+  let index = M.fromList (zip order [0 ..])
+  memos <- M.fromList <$> forM order (\name -> (,) name <$> newEmptyMVar)
+  queue <- newMVar order
+  forM_ [1 .. min jobs (length order)] $ \_ -> forkIO (worker varOf index memos queue)
+  forM_ [name | (name, _) <- vars, not (M.member name book)] $ \name -> putMVar (varOf M.! name) Missing
+  return vars
+  where
+    -- With `--universes`, each `*` of the book gets its own level variable
+    levelBook = if M.member "--universes" opts then bookLevels book else book
+    -- Checks termination too, per `--termination` (default: error), and
+    -- universe levels with `--universes`
+    checker name term
+      | M.member "--universes" opts = doCheckLevels name (total name term)
+      | otherwise                   = total name term
+    total name term = case M.lookup "--termination" opts of
+      _ | S.member name partials -> doCheck term
+      Just "off"                 -> doCheck term
+      Just "warn"                -> doCheckTotal False name term
+      _                          -> doCheckTotal True name term
+    -- Takes the next definition, and waits for the dependencies taken before
+    -- it (not for later ones, which only happen in mutual recursion)
+    worker varOf index memos queue = do
+      next <- modifyMVar queue (\names -> return (drop 1 names, listToMaybe names))
+      case next of
+        Nothing   -> return ()
+        Just name -> do
+          let term = levelBook M.! name
+          let deps = [dep | dep <- nub (getDeps term), maybe False (< index M.! name) (M.lookup dep index)]
+          memo <- M.unions <$> mapM (readMVar . (memos M.!)) deps
+          let cached = M.lookup name cache == Just (getCheckHash opts book name) && not (S.member name partials)
+          -- A crash (like an incomplete pattern) still fills the variables,
+          -- so that the printer and the dependents don't wait forever
+          job <- try $ if cached
+            then return (Cached, memo)
+            else do
+              ini    <- getCurrentTime
+              result <- evaluate (envRunMemo (checker name term) levelBook memo)
+              -- Forces all that is printed of the outcome, so that its lazy
+              -- errors are caught here, and its work timed
+              _      <- evaluate $ case result of
+                Done state _ -> forceState state
+                Fail state   -> forceState state
+              end    <- getCurrentTime
+              let time = realToFrac (diffUTCTime end ini)
+              case result of
+                Done state _ -> do
+                  memo' <- evaluate (M.union memo (doTrust state))
+                  return (Checked term result time, memo')
+                Fail state -> return (Checked term result time, memo)
+          let (outcome, memo') = case job of
+                Left err   -> (Crashed (displayException (err :: SomeException)), memo)
+                Right done -> done
+          putMVar (memos M.! name) memo'
+          putMVar (varOf M.! name) outcome
+          worker varOf index memos queue
+
+-- With `--universes`, adds the dependencies of the definitions to check, as
+-- the levels of the book's `*`s are shared, and solved together
+levelDeps :: Opts -> Book -> [String] -> [String]
+levelDeps opts book names
+  | M.member "--universes" opts = nub (names ++ [dep | name <- names, dep <- S.toList (getAllDeps book name), M.member dep book])
+  | otherwise                   = names
+
+-- With `--universes`, solves the level bounds of all checked definitions
+-- together, and prints the one that can't be satisfied, if any
+checkLevels :: Opts -> Book -> [(String, MVar Outcome)] -> IO (Either String ())
+checkLevels opts book checks
+  | not (M.member "--universes" opts) = return $ Right ()
+  | otherwise = do
+    bounds <- fmap concat $ forM checks $ \ (name, var) -> do
+      outcome <- readMVar var
+      return $ case outcome of
+        Checked _ (Done (State _ _ _ _ _ _ _ (Just bounds)) _) _ -> [(name, bound) | bound <- bounds]
+        _                                                        -> []
+    case solveLevels bounds of
+      Nothing -> do
+        return $ Right ()
+      Just (name, Bound lo hi) -> do
+        let err = Error Nothing (Uni hi) (Uni lo) (Ref name) 0
+        if isJSON opts
+          then putStrLn $ showInfoJSON book IM.empty err
+          else showInfo book IM.empty err >>= putStr
+        return $ Left "Error: Inconsistent universe levels."
+
+-- Prints outcomes in order, waiting for each one, and updates the cache
+printChecks :: Opts -> Book -> (Cache, [Either String ()]) -> [(String, MVar Outcome)] -> IO (Cache, [Either String ()])
+printChecks opts book = foldM $ \ (cache, results) (name, var) -> do
+  outcome <- readMVar var
+  case outcome of
+    Cached -> do
+      if isJSON opts
+        then putStrLn $ showJSON $ JObj [("kind", JStr "check"), ("name", JStr name), ("status", JStr "cached")]
+        else putStrLn $ "\x1b[32m✓ " ++ name ++ "\x1b[0m \x1b[2m(cached)\x1b[0m"
+      return (cache, results ++ [Right ()])
+    Checked term (Done state _) _ -> do
+      cliPrintCheck opts name term state True
+      let hash = getCheckHash opts book name
+      let cache' = if isClean term state then M.insert name hash cache else M.delete name cache
+      return (cache', results ++ [Right ()])
+    Checked term (Fail state) _ -> do
+      cliPrintCheck opts name term state False
+      return (M.delete name cache, results ++ [Left $ "Error."])
+    Crashed err -> do
+      if isJSON opts
+        then putStrLn $ showJSON $ JObj [("kind", JStr "check"), ("name", JStr name), ("status", JStr "crashed"), ("message", JStr err)]
+        else putStrLn $ "\x1b[31m✗ " ++ name ++ "\x1b[0m \x1b[2m(checker crashed: " ++ err ++ ")\x1b[0m"
+      return (M.delete name cache, results ++ [Left $ "Error."])
+    Missing -> do
+      return (cache, results ++ [Left $ "Definition not found: " ++ name])
+
+-- Prints the checker stats of the checked definitions, slowest first
+printStats :: Opts -> [(String, MVar Outcome)] -> IO ()
+printStats opts checks = do
+  rows <- fmap concat $ forM checks $ \ (name, var) -> do
+    outcome <- readMVar var
+    return $ case outcome of
+      Checked _ (Done state _) time -> [(name, time, state)]
+      Checked _ (Fail state) time   -> [(name, time, state)]
+      _                             -> []
+  let sorted = sortBy (comparing (\ (_, time, _) -> negate time)) rows
+  unless (isJSON opts) $ do
+    putStrLn $ "\x1b[1m" ++ statsRow ["time (ms)", "equal", "unify", "reduce", "solved", "postponed", "definition"] ++ "\x1b[0m"
+  forM_ sorted $ \ (name, time, State _ fill _ _ _ (Stats eqs uns reds susp) _ _) -> do
+    if isJSON opts
+      then putStrLn $ showJSON $ JObj
+        [ ("kind", JStr "stats"), ("name", JStr name), ("time", JNum time)
+        , ("equal", JNum (fromIntegral eqs)), ("unify", JNum (fromIntegral uns)), ("reduce", JNum (fromIntegral reds))
+        , ("solved", JNum (fromIntegral (IM.size fill))), ("postponed", JNum (fromIntegral susp)) ]
+      else putStrLn $ statsRow [show (round (time * 1000) :: Integer), show eqs, show uns, show reds, show (IM.size fill), show susp, name]
+  where
+    statsRow cols = concat [replicate (10 - length col) ' ' ++ col ++ "  " | col <- init cols] ++ last cols
+
+-- Cache
+-- -----
+
+-- Maps each definition to the hash it had when it last checked cleanly
+type Cache = M.Map String Word64
+
+-- Hashes a definition, and its dependencies, along with the modes that it is
+-- checked in, so that a definition that passed in a laxer mode (like
+-- `--termination off`) isn't taken as passed in a stricter one
+getCheckHash :: Opts -> Book -> String -> Word64
+getCheckHash opts book name = hashString (checkMode ++ show (getDefHash book name)) where
+  checkMode = concat
+    [ "termination=", M.findWithDefault "error" "--termination" opts
+    , if M.member "--universes" opts then " universes" else "" ]
+
+-- With `--stats`, nothing is taken from the cache, so that every definition
+-- is measured; the results are still saved over the loaded cache
+statsCache :: Opts -> Cache -> Cache
+statsCache opts cache = if M.member "--stats" opts then M.empty else cache
+
+-- Gets the path of the check cache, which lives next to the book directory
+getCachePath :: FilePath -> FilePath
+getCachePath bookPath = takeDirectory bookPath </> ".kindcache" </> "checked"
+
+-- Loads the check cache, or an empty one if there is none
+loadCache :: FilePath -> IO Cache
+loadCache bookPath = do
+  let cachePath = getCachePath bookPath
+  exists <- doesFileExist cachePath
+  if not exists
+    then return M.empty
     else do
-      code  <- readFile filePath
-      book0 <- doParseBook filePath code
-      let book1 = M.union book book0
-      let defs  = M.keys book0
-      let deps  = concatMap (getDeps . snd) (M.toList book0)
-      let defs' = M.singleton filePath defs
-      let deps' = M.singleton filePath deps
-      foldM (\ (depBook, depDefs, depDeps) dep -> do
-          (depBook', depDefs', depDeps') <- loadName bookPath depBook dep
-          return ( depBook' , M.union depDefs depDefs' , M.union depDeps depDeps')
-        ) (book1, defs', deps') deps
+      content <- readFile' cachePath
+      return $ M.fromList [ (nam, hash) | [hashStr, nam] <- map words (lines content), (hash, "") <- reads hashStr ]
+
+-- Saves the check cache
+saveCache :: FilePath -> Cache -> IO ()
+saveCache bookPath cache = do
+  let cachePath = getCachePath bookPath
+  createDirectoryIfMissing True (takeDirectory cachePath)
+  writeFile cachePath $ unlines [ show hash ++ " " ++ nam | (nam, hash) <- M.toList cache ]
+
+-- A run is clean when it logged nothing but warnings and left no unsolved metas
+isClean :: Term -> State -> Bool
+isClean term (State _ fill _ logs _ _ _ _) = all isWarning logs && countMetas term <= IM.size fill where
+  isWarning (Warning _ _ _ _) = True
+  isWarning _                 = False
 
 -- Utils
 -- -----
 
--- Finds the directory named "monobook"
+-- Checks if a name is a test definition: `#name`, or numbered `name#0`...
+isTestName :: String -> Bool
+isTestName name = case break (== '#') name of
+  (_, '#' : num) -> not (null num) && all isDigit num
+  _              -> False
+
+-- Finds the book roots: the ones given by `--book` (or else the ones declared
+-- by the nearest manifest, or else the nearest "kindbook" directory), followed
+-- by the ones listed in KIND_PATH
+findBookRoots :: Opts -> FilePath -> IO (Either String Roots)
+findBookRoots opts currPath = do
+  mainRoots <- case M.lookup "--book" opts of
+    Just dirs -> checkRootDirs "--book" (splitSearchPath dirs)
+    Nothing   -> do
+      manifestPath <- findManifest currPath
+      case manifestPath of
+        Just manifestPath -> loadManifestRoots manifestPath
+        Nothing           -> Right . maybe [] (\dir -> [("", dir)]) <$> findBookDir currPath
+  kindPath <- lookupEnv "KIND_PATH"
+  extraRoots <- checkRootDirs "KIND_PATH" (maybe [] splitSearchPath kindPath)
+  case (,) <$> mainRoots <*> extraRoots of
+    Left err -> return $ Left err
+    Right (mainRoots, extraRoots) ->
+      Right . nub <$> mapM (\ (prefix, dir) -> (,) prefix <$> canonicalizePath dir) (filter (not . null . snd) mainRoots ++ extraRoots)
+
+-- Checks that the book roots given by a setting (--book or KIND_PATH) exist
+checkRootDirs :: String -> [FilePath] -> IO (Either String Roots)
+checkRootDirs setting dirs = do
+  missing <- filterM (fmap not . doesDirectoryExist) dirs
+  return $ case missing of
+    []        -> Right [("", dir) | dir <- dirs]
+    (dir : _) -> Left $ "Error: The book root '" ++ dir ++ "' (from " ++ setting ++ ") doesn't exist."
+
+-- Finds the nearest directory named "kindbook", searching upwards
 findBookDir :: FilePath -> IO (Maybe FilePath)
 findBookDir dir = do
   let kindBookDir = dir </> "kindbook"
@@ -215,18 +1061,12 @@ findBookDir dir = do
       then return Nothing
       else findBookDir (takeDirectory dir)
 
--- Finds all Kind files in this directory tree
-findKindFiles :: FilePath -> IO [FilePath]
-findKindFiles dir = do
-  contents <- getDirectoryContents dir
-  let properNames = filter (`notElem` [".", ".."]) contents
-  paths <- forM properNames $ \name -> do
-    let path = dir </> name
-    isDirectory <- doesDirectoryExist path
-    if isDirectory
-      then findKindFiles path
-      else return [path | ".kind" `isSuffixOf` path]
-  return (concat paths)
+-- Gets the modification time of every Kind file in these directory trees
+getStamps :: [FilePath] -> IO (M.Map FilePath UTCTime)
+getStamps dirs = do
+  files <- nub . concat <$> mapM findKindFiles dirs
+  times <- forM files getModificationTime
+  return $ M.fromList (zip files times)
 
 -- Loads a file into a string
 readSource :: FilePath -> IO String
@@ -237,12 +1077,14 @@ readSource file = do
     Left er -> return $ "Could not read source file: " ++ file
 
 -- Extracts the definition name from a file path or name
-getDefName :: FilePath -> String -> String
-getDefName bookPath = dropBookPath . dropExtension where
+getDefName :: Roots -> String -> String
+getDefName roots = dropBookPath . dropExtension where
   dropExtension path
     | isExtensionOf "kind" path = System.FilePath.dropExtension path
     | otherwise                 = path
-  dropBookPath path = maybe path id (stripPrefix (bookPath++"/") path)
+  dropBookPath path = case findRoot roots path of
+    Just (prefix, bookPath) -> mountName prefix (drop (length bookPath + 1) path)
+    Nothing                 -> path
 
 -- Gets the full path for a definition
 getDefPath :: FilePath -> String -> FilePath
@@ -253,7 +1095,7 @@ getDefPath bookPath name = bookPath </> name ++ ".kind"
 
 showInfo :: Book -> Fill -> Info -> IO String
 showInfo book fill info = case info of
-  Found nam typ ctx dep ->
+  Found _ nam typ ctx dep ->
     let nam' = concat ["?", nam]
         typ' = showTermGo True (normal book fill 0 typ dep) dep
         ctx' = showContext book fill ctx dep
@@ -262,21 +1104,58 @@ showInfo book fill info = case info of
     let exp' = concat ["- expected : \x1b[32m", showTermGo True (normal book fill 0 exp dep) dep, "\x1b[0m"]
         det' = concat ["- detected : \x1b[31m", showTermGo True (normal book fill 0 det dep) dep, "\x1b[0m"]
         bad' = concat ["- origin   : \x1b[2m", showTermGo True (normal book fill 0 bad dep) dep, "\x1b[0m"]
-    (file, text) <- case src of
-      Just (Cod (Loc fileName iniLine iniCol) (Loc _ endLine endCol)) -> do
-        canonPath <- canonicalizePath fileName
-        content   <- readSource canonPath
-        let highlighted = highlightError (iniLine, iniCol) (endLine, endCol) content
-        return (canonPath, unlines $ take 8 $ lines highlighted)
-      Nothing -> return ("unknown_file", "Could not read source file.\n")
-    let src' = concat ["\x1b[4m", file, "\x1b[0m\n", text]
+    src' <- showSource src
     return $ concat ["\x1b[1mERROR:\x1b[0m\n", exp', "\n", det', "\n", bad', "\n", src']
+  Warning src msg bad dep -> do
+    let msg' = concat ["- warning  : \x1b[33m", msg, "\x1b[0m"]
+        bad' = concat ["- origin   : \x1b[2m", showTermGo True (normal book fill 0 bad dep) dep, "\x1b[0m"]
+    src' <- showSource src
+    return $ concat ["\x1b[1mWARNING:\x1b[0m\n", msg', "\n", bad', "\n", src']
   Solve nam val dep ->
     return $ concat ["SOLVE: _", show nam, " = ", showTermGo True val dep]
   Vague nam ->
     return $ concat ["VAGUE: _", nam]
   Print val dep ->
     return $ showTermGo True (normal book fill 2 val dep) dep
+  where
+    -- The highlighted lines of a source span, under its file's path
+    showSource src = do
+      (file, text) <- case src of
+        Just (Cod (Loc fileName iniLine iniCol) (Loc _ endLine endCol)) -> do
+          canonPath <- canonicalizePath fileName
+          content   <- readSource canonPath
+          let highlighted = highlightError (iniLine, iniCol) (endLine, endCol) content
+          return (canonPath, unlines $ take 8 $ lines highlighted)
+        Nothing -> return ("unknown_file", "Could not read source file.\n")
+      return $ concat ["\x1b[4m", file, "\x1b[0m\n", text]
+
+showInfoJSON :: Book -> Fill -> Info -> String
+showInfoJSON book fill info = showJSON $ case info of
+  Found src nam typ ctx dep -> JObj $
+    [ ("kind", JStr "found"), ("name", JStr nam) ] ++
+    srcJSON src ++
+    [ ("goal", JStr (norm typ dep)), ("context", JArr (map (\term -> ctxJSON term dep) ctx)) ]
+  Error src exp det bad dep -> JObj $
+    [ ("kind", JStr "error") ] ++
+    srcJSON src ++
+    [ ("expected", JStr (norm exp dep)), ("detected", JStr (norm det dep)), ("origin", JStr (norm bad dep)) ]
+  Warning src msg bad dep -> JObj $
+    [ ("kind", JStr "warning") ] ++
+    srcJSON src ++
+    [ ("message", JStr msg), ("origin", JStr (norm bad dep)) ]
+  Solve nam val dep -> JObj
+    [ ("kind", JStr "solve"), ("meta", JNum (fromIntegral nam)), ("value", JStr (showTermGo True val dep)) ]
+  Vague nam -> JObj
+    [ ("kind", JStr "vague"), ("name", JStr nam) ]
+  Print val dep -> JObj
+    [ ("kind", JStr "print"), ("value", JStr (showTermGo True (normal book fill 2 val dep) dep)) ]
+  where
+    norm term dep = showTermGo True (normal book fill 0 term dep) dep
+    srcJSON (Just cod@(Cod (Loc file _ _) _)) = [("file", JStr file), ("range", codToJSON cod)]
+    srcJSON Nothing                           = [("file", JNull), ("range", JNull)]
+    ctxJSON (Src _ val)     dep = ctxJSON val dep
+    ctxJSON (Ann _ val typ) dep = JObj [("term", JStr (norm val dep)), ("type", JStr (norm typ dep))]
+    ctxJSON term            dep = JObj [("term", JStr (norm term dep)), ("type", JNull)]
 
 showContext :: Book -> Fill -> [Term] -> Int -> String
 showContext book fill ctx dep = unlines $ map (\term -> "- " ++ showContextAnn book fill term dep) ctx
@@ -288,14 +1167,68 @@ showContextAnn book fill term              dep = showTermGo True (normal book fi
 
 -- Prints logs from the type-checker
 cliPrintLogs :: State -> IO ()
-cliPrintLogs (State book fill susp logs) = do
+cliPrintLogs (State book fill susp logs memo stats trace lvls) = do
   forM_ logs $ \log -> do
     result <- showInfo book fill log
     putStr result
 
+-- Prints logs from the type-checker as JSON lines
+cliPrintLogsJSON :: State -> IO ()
+cliPrintLogsJSON (State book fill susp logs memo stats trace lvls) = do
+  forM_ logs $ \log -> do
+    putStrLn $ showInfoJSON book fill log
+
+-- Prints the logs, warnings and result of checking a definition
+cliPrintCheck :: Opts -> String -> Term -> State -> Bool -> IO ()
+cliPrintCheck opts name term state@(State _ fill _ _ _ _ _ _) ok
+  | isJSON opts = do
+      cliPrintLogsJSON state
+      putStrLn $ showJSON $ JObj
+        [ ("kind", JStr "check")
+        , ("name", JStr name)
+        , ("status", JStr (if ok then "ok" else "fail"))
+        , ("unsolved", JNum (fromIntegral (max 0 (countMetas term - IM.size fill))))
+        ]
+  | otherwise = do
+      cliPrintLogs state
+      cliPrintWarn term state
+      if ok
+        then putStrLn $ "\x1b[32m✓ " ++ name ++ "\x1b[0m"
+        else putStrLn $ "\x1b[31m✗ " ++ name ++ "\x1b[0m"
+
+-- A traced checker step: its kind, terms, depth, result and nested steps
+data TraceStep = TraceStep String [String] Int String [TraceStep]
+
+-- Builds the tree of traced steps from their events, in order
+traceTree :: [Event] -> [TraceStep]
+traceTree events = fst (go events) where
+  go (Enter tag terms dep : events) =
+    let (kids, rest) = go events in case rest of
+      Leave res : rest' -> let (steps, rest'') = go rest' in (TraceStep tag terms dep res kids : steps, rest'')
+      _                 -> ([TraceStep tag terms dep "" kids], rest)
+  go events = ([], events)
+
+showTrace :: Int -> TraceStep -> String
+showTrace ind (TraceStep tag terms dep res kids) = concat
+  [ pad, "\x1b[1m", tag, "\x1b[0m \x1b[2m(dep ", show dep, ")\x1b[0m ", mark, "\n"
+  , concat [pad ++ "  \x1b[2m|\x1b[0m " ++ term ++ "\n" | term <- terms]
+  , concatMap (showTrace (ind + 1)) kids ]
+  where
+    pad  = replicate (ind * 2) ' '
+    mark = if res == "fail" then "\x1b[31m✗\x1b[0m" else "\x1b[32m✓\x1b[0m"
+
+traceJSON :: TraceStep -> JSON
+traceJSON (TraceStep tag terms dep res kids) = JObj
+  [ ("step", JStr tag)
+  , ("terms", JArr (map JStr terms))
+  , ("dep", JNum (fromIntegral dep))
+  , ("result", JStr res)
+  , ("steps", JArr (map traceJSON kids))
+  ]
+
 -- Prints a warning if there are unsolved metas
 cliPrintWarn :: Term -> State -> IO ()
-cliPrintWarn term (State _ fill _ _) = do
+cliPrintWarn term (State _ fill _ _ _ _ _ _) = do
   let metaCount = countMetas term
   let fillCount = IM.size fill
   if (metaCount > fillCount) then do
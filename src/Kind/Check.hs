@@ -4,15 +4,19 @@ module Kind.Check where
 
 import Kind.Env
 import Kind.Equal
+import Kind.Positivity
 import Kind.Reduce
 import Kind.Show
+import Kind.Termination
 import Kind.Type
+import Kind.Universe
 import Kind.Util
 
 import qualified Data.IntMap.Strict as IM
 import qualified Data.Map.Strict as M
 
 import Control.Monad (forM, forM_, unless, when)
+import Data.Maybe (fromMaybe, isJust)
 import Debug.Trace
 
 -- Type-Checking
@@ -20,20 +24,21 @@ import Debug.Trace
 
 -- Modes:
 -- - sus=True  : suspended checks on / better unification / wont return annotated term 
--- - sus=False : suspended checks off / worse unification / will return annotated term
+-- - sus=False : suspended checks off / worse unification / will return annotated term (keeping Src spans)
 
 infer :: Bool -> Maybe Cod -> Term -> Int -> Env Term
-infer sus src term dep = debug ("infer:" ++ (if sus then "* " else " ") ++ showTermGo False term dep) $ go term where
+infer sus src term dep = envTrace "infer" [showTermGo False term dep] dep $ go term where
 
   go (All nam inp bod) = do
-    inpA <- checkLater sus src inp Set dep
-    bodA <- checkLater sus src (bod (Ann False (Var nam dep) inp)) Set (dep + 1)
-    return $ Ann False (All nam inpA (\x -> bodA)) Set
+    (inpA, inpS) <- checkSort (checkLater sus) sus src inp dep
+    (bodA, bodS) <- checkSort (checkLater sus) sus src (bod (Ann False (Var nam dep) inp)) (dep + 1)
+    return $ Ann False (All nam inpA (\x -> bodA)) (sortMax inpS bodS)
 
   go (App fun arg) = do
     funA <- infer sus src fun dep
     book <- envGetBook
     fill <- envGetFill
+    envCount statsReduce
     case reduce book fill 2 (getType funA) of
       (All inpNam inpTyp inpBod) -> do
         argA <- checkLater sus src arg inpTyp dep
@@ -49,14 +54,15 @@ infer sus src term dep = debug ("infer:" ++ (if sus then "* " else " ") ++ showT
     return $ Ann False val typ
 
   go (Slf nam typ bod) = do
-    typA <- checkLater sus src typ Set dep
-    bodA <- checkLater sus src (bod (Ann False (Var nam dep) typ)) Set (dep + 1)
-    return $ Ann False (Slf nam typA (\x -> bodA)) Set
+    (typA, typS) <- checkSort (checkLater sus) sus src typ dep
+    (bodA, bodS) <- checkSort (checkLater sus) sus src (bod (Ann False (Var nam dep) typ)) (dep + 1)
+    return $ Ann False (Slf nam typA (\x -> bodA)) (sortMax typS bodS)
 
   go (Ins val) = do
     valA <- infer sus src val dep
     book <- envGetBook
     fill <- envGetFill
+    envCount statsReduce
     case reduce book fill 2 (getType valA) of
       (Slf slfNam slfTyp slfBod) -> do
         return $ Ann False (Ins valA) (slfBod (Ins valA))
@@ -65,23 +71,37 @@ infer sus src term dep = debug ("infer:" ++ (if sus then "* " else " ") ++ showT
         envFail
 
   go (Ref nam) = do
-    book <- envGetBook
-    case M.lookup nam book of
-      Just val -> do
-        valA <- infer sus src val dep
-        return $ Ann False (Ref nam) (getType valA)
+    memo <- envGetMemo
+    case M.lookup nam memo of
+      Just typ -> do
+        return $ Ann False (Ref nam) typ
       Nothing -> do
-        envLog (Error src (Ref "expression") (Ref "undefined") (Ref nam) dep)
-        envFail
+        book <- envGetBook
+        case M.lookup nam book of
+          Just val -> do
+            valA <- infer sus src val dep
+            -- Only a declared type without metas reads the same in every run
+            case val of
+              Ann _ _ typ | countMetas typ == 0 -> envMemo nam typ
+              _                                 -> return ()
+            return $ Ann False (Ref nam) (getType valA)
+          Nothing -> do
+            envLog (Error src (Ref "expression") (Ref "undefined") (Ref nam) dep)
+            envFail
 
   go Set = do
     return $ Ann False Set Set
 
+  go (Uni lvl) = do
+    return $ Ann False (Uni lvl) (Uni (levelSuc lvl))
+
   go U64 = do
-    return $ Ann False U64 Set
+    base <- lowestSort
+    return $ Ann False U64 base
 
   go F64 = do
-    return $ Ann False F64 Set
+    base <- lowestSort
+    return $ Ann False F64 base
 
   go (Num num) = do
     return $ Ann False (Num num) U64
@@ -108,6 +128,7 @@ infer sus src term dep = debug ("infer:" ++ (if sus then "* " else " ") ++ showT
       else do
         book <- envGetBook
         fill <- envGetFill
+        envCount statsReduce
         let reducedFst = reduce book fill 1 (getType fstT)
         let returnType = getOpReturnType opr reducedFst
         return $ Ann False (Op2 opr fstT sndT) returnType
@@ -117,8 +138,8 @@ infer sus src term dep = debug ("infer:" ++ (if sus then "* " else " ") ++ showT
     envFail
 
   go (Map typ) = do
-    typA <- checkLater sus src typ Set dep
-    return $ Ann False (Map typA) Set
+    (typA, typS) <- checkSort (checkLater sus) sus src typ dep
+    return $ Ann False (Map typA) typS
 
   go (KVs kvs dft) = do
     dftA <- infer sus src dft dep
@@ -131,6 +152,7 @@ infer sus src term dep = debug ("infer:" ++ (if sus then "* " else " ") ++ showT
     mapA <- infer sus src map dep
     book <- envGetBook
     fill <- envGetFill
+    envCount statsReduce
     case reduce book fill 2 (getType mapA) of
       (Map typ) -> do
         let got_ann = Ann False (Var got dep) typ
@@ -146,6 +168,7 @@ infer sus src term dep = debug ("infer:" ++ (if sus then "* " else " ") ++ showT
     mapA <- infer sus src map dep
     book <- envGetBook
     fill <- envGetFill
+    envCount statsReduce
     case reduce book fill 2 (getType mapA) of
       (Map typ) -> do
         valA <- check sus src val typ dep
@@ -168,10 +191,17 @@ infer sus src term dep = debug ("infer:" ++ (if sus then "* " else " ") ++ showT
 
   -- TODO: annotate inside ADT for completion (not needed)
   go (ADT scp cts typ) = do
+    book <- envGetBook
+    case positivity book typ cts dep of
+      Just (fsrc, fld) -> do
+        envLog (Error (maybe src Just fsrc) (Ref "strictly positive field") (Ref "negative occurrence") fld dep)
+        envFail
+      Nothing -> return ()
+    base <- lowestSort
     ctsA <- forM cts $ \ (Ctr cnam tele) -> do
-      teleA <- checkTele sus src tele Set dep
-      return $ Ctr cnam teleA
-    return $ Ann False (ADT scp ctsA typ) Set
+      (teleA, teleS) <- checkTele sus src tele Set dep
+      return (Ctr cnam teleA, teleS)
+    return $ Ann False (ADT scp (map fst ctsA) typ) (foldr (sortMax . snd) base ctsA)
 
   go (Con nam arg) = do
     envLog (Error src (Ref "annotation") (Ref "constructor") (Con nam arg) dep)
@@ -203,7 +233,10 @@ infer sus src term dep = debug ("infer:" ++ (if sus then "* " else " ") ++ showT
     envFail
 
   go (Src src val) = do
-    infer sus (Just src) val dep
+    valA <- infer sus (Just src) val dep
+    if sus
+      then return valA
+      else return $ Ann False (Src src valA) (getType valA)
 
   go tm@(Txt txt) = do
     return $ Ann False tm (Ref "String")
@@ -214,15 +247,23 @@ infer sus src term dep = debug ("infer:" ++ (if sus then "* " else " ") ++ showT
   go tm@(Nat val) = do
     book <- envGetBook
     fill <- envGetFill
+    envCount statsReduce
     go (reduce book fill 2 tm)
 
   go tm@(Lst lst) = do
     book <- envGetBook
     fill <- envGetFill
+    envCount statsReduce
     go (reduce book fill 2 tm)
 
 check :: Bool -> Maybe Cod -> Term -> Term -> Int -> Env Term
-check sus src term typx dep = debug ("check:" ++ (if sus then "* " else " ") ++ showTermGo False term dep ++ "\n    :: " ++ showTermGo True typx dep) $ go term where
+check sus src term typx dep = envTrace "check" [showTermGo False term dep, showTermGo True typx dep] dep $ do
+  levels <- envGetLevels
+  case (levels, typx) of
+    -- With universes on, `*` stands for any universe
+    (Just _, Set) -> fst <$> checkSort (check sus) sus src term dep
+    _             -> go term
+ where
 
   go (App (Src _ val) arg) =
     go (App val arg)
@@ -238,6 +279,7 @@ check sus src term typx dep = debug ("check:" ++ (if sus then "* " else " ") ++
   go (Lam nam bod) = do
     book <- envGetBook
     fill <- envGetFill
+    envCount statsReduce
     case reduce book fill 2 typx of
       (All typNam typInp typBod) -> do
         let ann = Ann False (Var nam dep) typInp
@@ -249,6 +291,7 @@ check sus src term typx dep = debug ("check:" ++ (if sus then "* " else " ") ++
   go (Ins val) = do
     book <- envGetBook
     fill <- envGetFill
+    envCount statsReduce
     case reduce book fill 2 typx of
       Slf typNam typTyp typBod -> do
         valA <- check sus src val (typBod (Ins val)) dep
@@ -258,6 +301,7 @@ check sus src term typx dep = debug ("check:" ++ (if sus then "* " else " ") ++
   go val@(Con nam arg) = do
     book <- envGetBook
     fill <- envGetFill
+    envCount statsReduce
     case reduce book fill 2 typx of
       (ADT adtScp adtCts adtTyp) -> do
         case lookup nam (map (\(Ctr cNam cTel) -> (cNam, cTel)) adtCts) of
@@ -271,7 +315,7 @@ check sus src term typx dep = debug ("check:" ++ (if sus then "* " else " ") ++
     where
       checkConstructor :: Maybe Cod -> [(Maybe String, Term)] -> Tele -> Int -> Env [(Maybe String, Term)]
       checkConstructor src [] (TRet ret) dep = do
-        cmp src val ret typx dep
+        cmpFlipped src val typx ret dep
         return []
       checkConstructor src ((field, arg):args) (TExt nam inp bod) dep =
         case field of
@@ -294,8 +338,10 @@ check sus src term typx dep = debug ("check:" ++ (if sus then "* " else " ") ++
   go (Mat cse) = do
     book <- envGetBook
     fill <- envGetFill
+    envCount statsReduce
     case reduce book fill 2 typx of
       (All typNam typInp typBod) -> do
+        envCount statsReduce
         case reduce book fill 2 typInp of
           (ADT adtScp adtCts adtTyp) -> do
             -- Checks if all cases are well-typed
@@ -311,6 +357,7 @@ check sus src term typx dep = debug ("check:" ++ (if sus then "* " else " ") ++
               else case M.lookup cNam adtCtsMap of
                 Just cTel -> do
                   let a_r = teleToTerms cTel dep
+                  envCount (statsReduce . statsReduce)
                   let eqs = zip (getDatIndices (reduce book fill 2 typInp)) (getDatIndices (reduce book fill 2 (snd a_r)))
                   let rt0 = teleToType cTel (typBod (Ann False (Con cNam (fst a_r)) typInp)) dep
                   let rt1 = foldl' (\ ty (a,b) -> replace a b ty dep) rt0 eqs
@@ -334,8 +381,10 @@ check sus src term typx dep = debug ("check:" ++ (if sus then "* " else " ") ++
   go (Swi zer suc) = do
     book <- envGetBook
     fill <- envGetFill
+    envCount statsReduce
     case reduce book fill 2 typx of
       (All typNam typInp typBod) -> do
+        envCount statsReduce
         case reduce book fill 2 typInp of
           U64 -> do
             -- Check zero case
@@ -352,6 +401,7 @@ check sus src term typx dep = debug ("check:" ++ (if sus then "* " else " ") ++
   go (KVs kvs dft) = do
     book <- envGetBook
     fill <- envGetFill
+    envCount statsReduce
     case reduce book fill 2 typx of
       (Map typ) -> do
         dftA <- check sus src dft typ dep
@@ -365,6 +415,7 @@ check sus src term typx dep = debug ("check:" ++ (if sus then "* " else " ") ++
     mapA <- infer sus src map dep
     book <- envGetBook
     fill <- envGetFill
+    envCount statsReduce
     case reduce book fill 2 (getType mapA) of
       (Map typ) -> do
         let got_ann = Ann False (Var got dep) typ
@@ -380,6 +431,7 @@ check sus src term typx dep = debug ("check:" ++ (if sus then "* " else " ") ++
     mapA <- infer sus src map dep
     book <- envGetBook
     fill <- envGetFill
+    envCount statsReduce
     case reduce book fill 2 (getType mapA) of
       (Map typ) -> do
         valA <- check sus src val typ dep
@@ -401,7 +453,7 @@ check sus src term typx dep = debug ("check:" ++ (if sus then "* " else " ") ++
     check sus src (bod val) typx dep
 
   go (Hol nam ctx) = do
-    envLog (Found nam typx ctx dep)
+    envLog (Found src nam typx ctx dep)
     return $ Ann False (Hol nam ctx) typx
 
   go (Met uid spn) = do
@@ -421,49 +473,99 @@ check sus src term typx dep = debug ("check:" ++ (if sus then "* " else " ") ++
   go tm@(Nat val) = do
     book <- envGetBook
     fill <- envGetFill
+    envCount statsReduce
     go (reduce book fill 2 tm)
 
   go tm@(Lst lst) = do
     book <- envGetBook
     fill <- envGetFill
+    envCount statsReduce
     go (reduce book fill 2 tm)
 
   go (Ann True val typ) = do
-    cmp src val typ typx dep
+    cmpFlipped src val typx typ dep
     check sus src val typ dep
 
   go (Ann False val typ) = do
-    cmp src val typ typx dep -- FIXME: should this be here?
+    cmpFlipped src val typx typ dep -- FIXME: should this be here?
     return $ Ann False val typ
 
   go (Src src val) = do
-    check sus (Just src) val typx dep
+    valA <- check sus (Just src) val typx dep
+    if sus
+      then return valA
+      else return $ Ann False (Src src valA) (getType valA)
 
   go term = do
     termA <- infer sus src term dep
     cmp src term typx (getType termA) dep
     return termA
 
-  cmp src term expected detected dep = do
-    equal <- equal expected detected dep
+  -- Checks that the detected type fits in the expected one
+  cmp src term expected detected dep =
+    cmpOr (Error src expected detected term dep) expected detected dep
+
+  -- Like `cmp`, but reports the two types the other way around, as errors on
+  -- annotations and constructors always have (the annotation, or the
+  -- constructor's type, as the expected one)
+  cmpFlipped src term expected detected dep =
+    cmpOr (Error src detected expected term dep) expected detected dep
+
+  cmpOr err expected detected dep = do
+    equal <- subsume expected detected dep
     if equal then do
       susp <- envTakeSusp
       forM_ susp $ \ (Check src val typ dep) -> do
         check sus src val typ dep
       return ()
     else do
-      envLog (Error src expected detected term dep)
+      envLog err
       envFail
 
-checkTele :: Bool -> Maybe Cod -> Tele -> Term -> Int -> Env Tele
+-- Checks a constructor's telescope, returning it with the universe of its fields
+checkTele :: Bool -> Maybe Cod -> Tele -> Term -> Int -> Env (Tele, Term)
 checkTele sus src tele typ dep = case tele of
   TRet term -> do
     termA <- check sus src term typ dep
-    return $ TRet termA
+    base  <- lowestSort
+    return (TRet termA, base)
   TExt nam inp bod -> do
-    inpA <- check sus src inp Set dep
-    bodA <- checkTele sus src (bod (Ann False (Var nam dep) inp)) typ (dep + 1)
-    return $ TExt nam inpA (\x -> bodA)
+    (inpA, inpS) <- checkSort (check sus) sus src inp dep
+    (bodA, bodS) <- checkTele sus src (bod (Ann False (Var nam dep) inp)) typ (dep + 1)
+    return (TExt nam inpA (\x -> bodA), sortMax inpS bodS)
+
+-- Checks that a term is a type with `chk` (`check` or `checkLater`), and
+-- returns it with its universe: `*`, unless universes are on, when the
+-- universe is inferred instead
+checkSort :: (Maybe Cod -> Term -> Term -> Int -> Env Term) -> Bool -> Maybe Cod -> Term -> Int -> Env (Term, Term)
+checkSort chk sus src term dep = do
+  levels <- envGetLevels
+  case levels of
+    Nothing -> do
+      termA <- chk src term Set dep
+      return (termA, Set)
+    Just _ -> do
+      termA <- infer sus src term dep
+      book  <- envGetBook
+      fill  <- envGetFill
+      envCount statsReduce
+      case reduce book fill 2 (getType termA) of
+        Uni lvl -> do
+          return (termA, Uni lvl)
+        other -> do
+          envLog (Error src (Ref "universe") other term dep)
+          envFail
+
+-- The universe of a type built from types in two universes
+sortMax :: Term -> Term -> Term
+sortMax (Uni aLvl) (Uni bLvl) = Uni (levelMax aLvl bLvl)
+sortMax _          _          = Set
+
+-- The universe of base types: `*`, or `*0` with universes on
+lowestSort :: Env Term
+lowestSort = do
+  levels <- envGetLevels
+  return $ if isJust levels then Uni (Level 0 []) else Set
 
 checkUnreachable :: Maybe Cod -> String -> Term -> Int -> Env (String, Term)
 checkUnreachable src cNam term dep = go src cNam term dep where
@@ -471,7 +573,7 @@ checkUnreachable src cNam term dep = go src cNam term dep where
   go src cNam (Let nam val bod) dep = go src cNam (bod (Con "void" [])) (dep+1)
   go src cNam (Use nam val bod) dep = go src cNam (bod (Con "void" [])) (dep+1)
   go _   cNam (Src src val)     dep = go (Just src) cNam val dep
-  go src cNam (Hol nam ctx)     dep = envLog (Found nam (Hol "unreachable" []) ctx dep) >> go src cNam Set dep
+  go src cNam (Hol nam ctx)     dep = envLog (Found src nam (Hol "unreachable" []) ctx dep) >> go src cNam Set dep
   go src cNam term              dep = return (cNam, Ann False Set U64)
 
 checkLater :: Bool -> Maybe Cod -> Term -> Term -> Int -> Env Term
@@ -495,9 +597,69 @@ doCheckMode sus term = do
 doCheck :: Term -> Env Term
 doCheck = doCheckMode True
 
+-- Checks a definition, then that its recursion terminates. A non-terminating
+-- call fails the check with an error when `strict`, and is a warning otherwise.
+doCheckTotal :: Bool -> String -> Term -> Env Term
+doCheckTotal strict name term = do
+  termA <- doCheck term
+  book  <- envGetBook
+  case termination book name term of
+    Nothing -> do
+      return termA
+    Just (src, call) -> do
+      if strict
+        then envLog (Error src (Ref "decreasing call") (Ref "non-terminating") call 0) >> envFail
+        else envLog (Warning src "non-terminating call" call 0) >> return termA
+
+-- Checks a definition with universes on, then that the level bounds it
+-- recorded have a solution (the CLI also solves those of all definitions)
+doCheckLevels :: String -> Env Term -> Env Term
+doCheckLevels name chk = do
+  envLevels
+  termA  <- chk
+  levels <- envGetLevels
+  case solveLevels [(name, bound) | bound <- fromMaybe [] levels] of
+    Nothing -> do
+      return termA
+    Just (_, Bound lo hi) -> do
+      envLog (Error Nothing (Uni hi) (Uni lo) (Ref name) 0)
+      envFail
+
 doAnnotate :: Term -> Env (Term, Fill)
 doAnnotate term = do
   doCheckMode True term
   term <- doCheckMode False term
   fill <- envGetFill
   return (bind term [], fill)
+
+-- Like `doAnnotate`, but also returns the state after the check, whose logs
+-- the annotation pass would otherwise repeat
+doCheckAnnotate :: Term -> Env (State, (Term, Fill))
+doCheckAnnotate term = do
+  doCheckMode True term
+  state <- envSnapshot
+  term  <- doCheckMode False term
+  fill  <- envGetFill
+  return (state, (bind term [], fill))
+
+-- Keeps the memoized types of a finished run, so that later runs can trust
+-- them instead of re-inferring the definitions. These are declared types
+-- without metas, as written, so they don't depend on the run's solutions.
+doTrust :: State -> Memo
+doTrust (State _ _ _ _ memo _ _ _) = memo
+
+-- Forces what reporting a run evaluates: its logs (normalized under its
+-- solutions), solutions, stats and level bounds. The checker leaves some
+-- errors, like an ill-formed match, in thunks; this raises them where the
+-- run is guarded and timed, rather than where it is printed.
+forceState :: State -> Int
+forceState (State book fill _ logs _ (Stats eqs uns reds susp) _ lvls) =
+  eqs + uns + reds + susp + maybe 0 length lvls + sum (map info logs) + sum [length (showTermGo True val 0) | val <- IM.elems fill]
+  where
+    info (Found _ nam typ ctx dep) = length nam + sum [norm term dep | term <- typ : ctx]
+    info (Solve _ val dep)         = length (showTermGo True val dep)
+    info (Error _ exp det bad dep) = sum [norm term dep | term <- [exp, det, bad]]
+    info (Warning _ msg bad dep)   = length msg + norm bad dep
+    info (Vague nam)               = length nam
+    info (Print val dep)           = length (showTermGo True (normal book fill 2 val dep) dep)
+    norm term dep = length (showTermGo True (normal book fill 0 term dep) dep)
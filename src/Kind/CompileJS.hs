@@ -171,6 +171,8 @@ termToCT book fill term typx dep = bindCT (t2ct term typx dep) [] where
       t2ct (bod val) typx dep
     go Set =
       CSet
+    go (Uni _) =
+      CSet
     go U64 =
       CU64
     go F64 =
@@ -0,0 +1,175 @@
+-- //./Type.hs//
+
+-- Deriving. A `data` declaration followed by `deriving (Eq, Show, Ord, DecEq)`
+-- gets, for each class, a definition with the class's suffix:
+-- - `T/eq      : ∀(a: T) ∀(b: T) Bool`
+-- - `T/show    : ∀(x: T) String`
+-- - `T/compare : ∀(a: T) ∀(b: T) Cmp`, ordering by constructor, then fields
+-- - `T/deq     : ∀(a: T) ∀(b: T) (Dec (Equal T a b))`
+-- Parametric types take, after their parameters, the instance of the class
+-- for each parameter of type `*`. The instance for a field's type `(F x y)`
+-- is `(F/eq x y ...)`, with the instances of the arguments that are types,
+-- as told by the parameter types of `F`'s declaration.
+
+module Kind.Derive where
+
+import Control.Monad (forM)
+import Data.List (intersperse)
+import Kind.Elim
+import Kind.Reduce
+import Kind.Show
+import Kind.Type
+import Kind.Util
+
+-- Generates the definitions of a `deriving` clause, given the declarations
+-- of the fields' types
+deriveDefs :: (String -> Maybe Term) -> String -> [String] -> Term -> Either String [(String, Term)]
+deriveDefs decl name classes term = case adtParts term of
+  Nothing                    -> Left ("deriving for " ++ name ++ ", which isn't a data declaration")
+  Just (Parts _ (_ : _) _ _) -> Left ("deriving for " ++ name ++ ", which has indices")
+  Just parts -> forM classes $ \ cls -> case cls of
+    "Eq"    -> deriveDef decl name parts "eq" deriveEq
+    "Show"  -> deriveDef decl name parts "show" deriveShow
+    "Ord"   -> deriveDef decl name parts "compare" deriveOrd
+    "DecEq" -> deriveDef decl name parts "deq" deriveDecEq
+    _       -> Left ("a derivable class (Eq, Show, Ord or DecEq), not " ++ cls)
+
+-- The name of the declaration that a derived definition belongs to
+deriveBase :: String -> Maybe String
+deriveBase name = case break (== '/') (reverse name) of
+  (suffix, '/' : base) | reverse suffix `elem` ["eq", "show", "compare", "deq"] && not (null base) -> Just (reverse base)
+  _ -> Nothing
+
+-- A class: the type of its instances for a type, and the cases of a
+-- constructor (given the instances for the fields' types)
+data Class = Class (Term -> Term) (Derive -> Either String Term)
+
+-- What a class's cases are derived from: the declaration, the constructor's
+-- index, and the instances for a type
+data Derive = Derive String Parts Int (Term -> Either String Term)
+
+deriveDef :: (String -> Maybe Term) -> String -> Parts -> String -> ([String] -> Class) -> Either String (String, Term)
+deriveDef decl name parts@(Parts pars _ ctrs used) suffix cls = do
+  let Class insTyp cases = cls used
+  let insts = [(par, fresh used (par ++ "." ++ suffix)) | (par, typ) <- pars, isSet typ]
+  cses <- forM (zip [0 ..] ctrs) $ \ (idx, (cnam, _, _)) -> do
+    cse <- cases (Derive name parts idx (deriveInst decl suffix insts))
+    return (cnam, cse)
+  let typ = alls pars $ alls [(ins, insTyp (Var par 0)) | (par, ins) <- insts] $ insTyp (selfType name pars [])
+  let val = lams (map fst pars ++ map snd insts) (Mat cses)
+  return (name ++ "/" ++ suffix, bind (Ann False val typ) [])
+
+-- The instance of a class for a type: a parameter's instance, or the
+-- definition with the class's suffix, applied to the type's arguments and to
+-- the instances of those that are types. Whether an argument is a type is
+-- told by the parameter it is passed to in the type's declaration, since a
+-- value argument can be headed by a `Ref` too (as in `(Vec A Nat/zero)`)
+deriveInst :: (String -> Maybe Term) -> String -> [(String, String)] -> Term -> Either String Term
+deriveInst decl suffix insts typ = case typ of
+  Src _ val   -> deriveInst decl suffix insts val
+  Ann _ val _ -> deriveInst decl suffix insts val
+  Var nam _ | Just ins <- lookup nam insts -> Right (Var ins 0)
+  U64 -> Right (Ref ("U64/" ++ suffix))
+  F64 -> Right (Ref ("F64/" ++ suffix))
+  _   -> case getSpine typ of
+    (Ref nam, args) -> do
+      let kinds = maybe [] declKinds (decl nam) ++ repeat False
+      inss <- forM [arg | (arg, True) <- zip args kinds] (deriveInst decl suffix insts)
+      return $ apps (Ref (nam ++ "/" ++ suffix)) (args ++ inss)
+    _ -> Left ("a field type with a `" ++ suffix ++ "` instance, not " ++ showTerm typ)
+
+-- Whether each parameter of a declaration is a type, from its annotation
+declKinds :: Term -> [Bool]
+declKinds (Src _ val)   = declKinds val
+declKinds (Ann _ _ typ) = go typ where
+  go (Src _ val)       = go val
+  go (All nam inp bod) = isSet inp : go (bod (Var nam 0))
+  go _                 = []
+declKinds _             = []
+
+isSet :: Term -> Bool
+isSet (Src _ typ) = isSet typ
+isSet Set         = True
+isSet _           = False
+
+-- Classes
+-- -------
+
+-- Equal when the constructors and their fields are
+deriveEq :: [String] -> Class
+deriveEq used = Class insTyp cases where
+  insTyp typ = All (fresh used "a") typ (\_ -> All (fresh used "b") typ (\_ -> Ref "Bool"))
+  cases (Derive _ (Parts _ _ ctrs _) idx inst) = do
+    let (cnam, flds, _) = ctrs !! idx
+    eqs <- forM flds $ \ (fld, typ) -> do
+      ins <- inst typ
+      return $ apps ins [Var fld 0, Var (other fld) 0]
+    return $ lams (map fst flds) $ Mat $
+      [(cnam, lams (map (other . fst) flds) (foldr both (Con "True" []) eqs))] ++
+      [("_", Lam "_" (\_ -> Con "False" [])) | length ctrs > 1]
+  both eq acc = App (Mat [("True", acc), ("False", Con "False" [])]) eq
+  other fld = fresh used (fld ++ ".b")
+
+-- Renders a value like a constructor: `#C{x y}`
+deriveShow :: [String] -> Class
+deriveShow used = Class insTyp cases where
+  insTyp typ = All (fresh used "x") typ (\_ -> Ref "String")
+  cases (Derive _ (Parts _ _ ctrs _) idx inst) = do
+    let (cnam, flds, _) = ctrs !! idx
+    strs <- forM flds $ \ (fld, typ) -> do
+      ins <- inst typ
+      return $ App ins (Var fld 0)
+    let pieces = [Txt ("#" ++ cnam ++ "{")] ++ intersperse (Txt " ") strs ++ [Txt "}"]
+    return $ lams (map fst flds) (foldr1 (\ piece acc -> apps (Ref "String/concat") [piece, acc]) pieces)
+
+-- Orders by constructor (in declaration order), then by fields, lexicographically
+deriveOrd :: [String] -> Class
+deriveOrd used = Class insTyp cases where
+  insTyp typ = All (fresh used "a") typ (\_ -> All (fresh used "b") typ (\_ -> Ref "Cmp"))
+  cases (Derive _ (Parts _ _ ctrs _) idx inst) = do
+    let (_, flds, _) = ctrs !! idx
+    cmps <- forM flds $ \ (fld, typ) -> do
+      ins <- inst typ
+      return $ apps ins [Var fld 0, Var (other fld) 0]
+    return $ lams (map fst flds) $ Mat
+      [ (onam, lams (map (other . fst) oflds) (order oidx cmps))
+      | (oidx, (onam, oflds, _)) <- zip [0 ..] ctrs ]
+    where
+      order oidx cmps
+        | oidx > idx = Con "LT" []
+        | oidx < idx = Con "GT" []
+        | otherwise  = foldr lexi (Con "EQ" []) cmps
+  lexi cmp acc = App (Mat [("LT", Con "LT" []), ("EQ", acc), ("GT", Con "GT" [])]) cmp
+  other fld = fresh used (fld ++ ".b")
+
+-- Decides equality: different constructors are never equal (their `Refl`
+-- case is unreachable), and the same constructor is equal when its fields
+-- are, since a proof for a field is rewritten into the others, and a proof for
+-- the values projects to one for the field
+deriveDecEq :: [String] -> Class
+deriveDecEq used = Class insTyp cases where
+  insTyp typ = All a typ (\_ -> All b typ (\_ -> App (Ref "Dec") (apps (Ref "Equal") [typ, Var a 0, Var b 0])))
+  cases (Derive name (Parts pars _ ctrs _) idx inst) = do
+    let (cnam, flds, _) = ctrs !! idx
+    decs <- forM flds $ \ (fld, typ) -> do
+      ins <- inst typ
+      return $ apps ins [Var fld 0, Var (other fld) 0]
+    let self = selfType name pars []
+    let con nams = Ann False (Con cnam [(Nothing, Var nam 0) | nam <- nams]) self
+    let proj k = Mat $
+          [(cnam, lams (map fst flds) (Var (fst (flds !! k)) 0))] ++
+          [("_", Lam "_" (\_ -> Var (fst (flds !! k)) 0)) | length ctrs > 1]
+    let field k = Ann True (App (Mat [("Refl", Con "Refl" [])]) (Var prf 0)) $ apps (Ref "Equal")
+          [snd (flds !! k), App (proj k) (con (map fst flds)), App (proj k) (con (map fst (take k flds) ++ map (other . fst) (drop k flds)))]
+    let decide k dec acc = App (Mat
+          [ ("Yes", Lam prf (\_ -> App (Mat [("Refl", acc)]) (Var prf 0)))
+          , ("No", Lam neg (\_ -> Con "No" [(Nothing, Lam prf (\_ -> App (Var neg 0) (field k)))])) ]) dec
+    return $ lams (map fst flds) $ Mat
+      [ (onam, lams (map (other . fst) oflds) (if oidx == idx then foldr (uncurry decide) (Con "Yes" [(Nothing, Con "Refl" [])]) (zip [0 ..] decs) else differ))
+      | (oidx, (onam, oflds, _)) <- zip [0 ..] ctrs ]
+  differ = Con "No" [(Nothing, Lam prf (\_ -> App (Mat [("Refl", Set)]) (Var prf 0)))]
+  other fld = fresh used (fld ++ ".b")
+  a   = fresh used "a"
+  b   = fresh used "b"
+  prf = fresh used "e"
+  neg = fresh used "ne"
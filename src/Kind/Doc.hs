@@ -0,0 +1,144 @@
+-- //./Type.hs//
+
+-- Documentation generator. Renders a static HTML site for the main book root:
+-- an index grouping the definitions by directory, and a page per definition
+-- with its type, its doc comment (the `//` lines right above it), the
+-- constructors of its `data` declaration, and links to its dependencies and
+-- reverse dependencies.
+
+module Kind.Doc where
+
+import Control.Monad (forM, forM_)
+import Data.Char (isSpace, ord)
+import Data.List (isPrefixOf, nub, sort)
+import Kind.Format
+import Kind.JSON
+import Kind.Load
+import Kind.Parse
+import Kind.Show
+import Kind.Type
+import Kind.Util
+import System.Directory (createDirectoryIfMissing)
+import System.FilePath (takeDirectory, (</>))
+import System.IO (readFile')
+import qualified Data.Map.Strict as M
+
+-- Generates the site for the loaded main book root into `--out` (by
+-- default, `doc` next to the book)
+runDoc :: Opts -> Roots -> FileCtx -> IO (Either String ())
+runDoc opts roots (book, defs, _, _) = do
+  let bookPath = snd (head roots)
+  let outPath  = M.findWithDefault (takeDirectory bookPath </> "doc") "--out" opts
+  files <- findKindFiles bookPath
+  comments <- fmap M.unions $ forM files $ \file -> docComments <$> readFile' file
+  let names = sort $ nub [name | file <- files, name <- M.findWithDefault [] file defs, M.member name book]
+  let deps  = M.fromList [(name, nub [dep | dep <- getDeps term, dep /= name, M.member dep book]) | (name, term) <- M.toList book]
+  let rdeps = M.fromListWith (++) [(dep, [name]) | (name, ds) <- M.toList deps, dep <- ds]
+  let page name = docPage names name (book M.! name) (M.lookup name comments) (M.findWithDefault [] name deps) (sort (M.findWithDefault [] name rdeps))
+  forM_ names $ \name -> do
+    let path = outPath </> docFile name
+    createDirectoryIfMissing True (takeDirectory path)
+    writeFile path (page name)
+  createDirectoryIfMissing True outPath
+  writeFile (outPath </> "index.html") (docIndex names)
+  if isJSON opts
+    then putStrLn $ showJSON $ JObj [("kind", JStr "doc"), ("path", JStr outPath), ("definitions", JNum (fromIntegral (length names)))]
+    else putStrLn $ "\x1b[32m✓ " ++ outPath ++ " (" ++ show (length names) ++ " definitions)\x1b[0m"
+  return $ Right ()
+
+-- Finds the doc comments of the definitions of a source file
+docComments :: String -> M.Map String String
+docComments code = go [] (lines code) where
+  uses = [(short, long) | (long, short) <- fst (sourceUses code)]
+  go doc [] = M.empty
+  go doc (line : rest)
+    | "//" `isPrefixOf` line = go (doc ++ [dropWhile isSpace (drop 2 line)]) rest
+    | otherwise = case header line of
+      Just name | not (null doc) -> M.insertWith (\ _ old -> old) name (unlines doc) (go [] rest)
+      _                          -> go [] rest
+  header line = case words line of
+    _ | null line || isSpace (head line) -> Nothing
+    ("#partial" : word : _)              -> Just (expandUses uses (takeWhile formatNameChar word))
+    ("data" : word : _)                  -> Just (expandUses uses (takeWhile formatNameChar word))
+    (word : _) | word /= "use"           -> Just (expandUses uses (takeWhile formatNameChar word))
+    _                                    -> Nothing
+
+-- Pages
+-- -----
+
+docIndex :: [String] -> String
+docIndex names = docLayout "Index" $ concat
+  [ concat
+    [ "<h2>", docEscape dir, "</h2>\n<ul>\n"
+    , concat ["<li>" ++ docLink "" name ++ "</li>\n" | name <- names, docGroup name == dir]
+    , "</ul>\n" ]
+  | dir <- nub (map docGroup names) ]
+
+docPage :: [String] -> String -> Term -> Maybe String -> [String] -> [String] -> String
+docPage names name term comment deps rdeps = docLayout name $ concat
+  [ "<p><a href=\"", docRoot name, "index.html\">index</a></p>\n"
+  , "<h1>", docEscape name, "</h1>\n"
+  , case term of
+      Ann _ _ typ -> "<pre>" ++ docEscape (showTerm typ) ++ "</pre>\n"
+      _           -> ""
+  , maybe "" (\doc -> "<p>" ++ docEscape doc ++ "</p>\n") comment
+  , docList "Constructors" [docEscape (docCtr ctr) | ctr <- getDefCtrs term]
+  , docList "Dependencies" (map refLink deps)
+  , docList "Used by" (map refLink rdeps)
+  ]
+  where
+    refLink dep
+      | dep `elem` names = docLink (docRoot name) dep
+      | otherwise        = docEscape dep
+
+docCtr :: Ctr -> String
+docCtr (Ctr nam tele) = unwords (('#' : nam) : [concat ["(", fld, ": ", showTermGo True typ 0, ")"] | (fld, typ) <- getTeleFields tele 0 []])
+
+docList :: String -> [String] -> String
+docList title []    = ""
+docList title items = concat ["<h3>", title, "</h3>\n<ul>\n", concatMap (\item -> "<li>" ++ item ++ "</li>\n") items, "</ul>\n"]
+
+docLayout :: String -> String -> String
+docLayout title body = concat
+  [ "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n"
+  , "<title>", docEscape title, "</title>\n"
+  , "<style>body { font-family: sans-serif; max-width: 60em; margin: auto; } pre { background: #f4f4f4; padding: 1em; overflow-x: auto; }</style>\n"
+  , "</head>\n<body>\n", body, "</body>\n</html>\n" ]
+
+-- Utils
+-- -----
+
+-- The directory a definition is grouped under
+docGroup :: String -> String
+docGroup name = if '/' `elem` name then takeDirectory name else "/"
+
+-- The relative path from a definition's page to the site's root
+docRoot :: String -> String
+docRoot name = concat (replicate (length (filter (== '/') name)) "../")
+
+-- Links to a definition's page. Its file name is percent-encoded, and so is
+-- the link to it, as the browser decodes links before looking files up
+docLink :: String -> String -> String
+docLink root name = concat ["<a href=\"", root, docEncode (docFile name), "\">", docEscape name, "</a>"]
+
+-- The file of a definition's page, like `Nat/add.html`, or `foo%230.html` for
+-- the test `foo#0`
+docFile :: String -> FilePath
+docFile name = docEncode name ++ ".html"
+
+-- Percent-encodes the characters that aren't safe in a URL path (keeping the
+-- `/`s between directories)
+docEncode :: String -> String
+docEncode = concatMap encode where
+  encode c
+    | c `elem` "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789/_.-" = [c]
+    | otherwise = '%' : [hex (ord c `div` 16), hex (ord c `mod` 16)]
+  hex n = "0123456789ABCDEF" !! n
+
+docEscape :: String -> String
+docEscape = concatMap escape where
+  escape '<'  = "&lt;"
+  escape '>'  = "&gt;"
+  escape '&'  = "&amp;"
+  escape '"'  = "&quot;"
+  escape c    = [c]
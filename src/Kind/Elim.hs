@@ -0,0 +1,130 @@
+-- //./Type.hs//
+
+-- Eliminators. For each `data` declaration `T`, the loader adds:
+-- - `T/elim`: the dependent eliminator (induction principle), taking the
+--   parameters, a motive `P` over the indices and a value of `T`, a case per
+--   constructor (with an induction hypothesis per recursive field), the
+--   indices, and the value, and returning `(P indices value)`;
+-- - `T/fold`: the non-dependent fold, whose motive only takes the indices,
+--   and whose cases receive the folded results in place of recursive fields.
+
+module Kind.Elim where
+
+import Kind.Reduce
+import Kind.Type
+import Kind.Util
+import qualified Data.Map.Strict as M
+
+-- Generates the eliminators and folds of the `data` declarations of a book
+bookElims :: Book -> Book
+bookElims book = M.fromList $ concat
+  [ [(name ++ "/elim", adtElim name parts), (name ++ "/fold", adtFold name parts)]
+  | (name, term) <- M.toList book
+  , Just parts <- [adtParts term] ]
+
+-- The name of the declaration that an eliminator or a fold belongs to
+elimBase :: String -> Maybe String
+elimBase name = case reverse name of
+  'm':'i':'l':'e':'/':base | not (null base) -> Just (reverse base)
+  'd':'l':'o':'f':'/':base | not (null base) -> Just (reverse base)
+  _                                          -> Nothing
+
+-- Parts of a declaration
+-- ----------------------
+
+-- A declaration's parameters and indices, its constructors (with their fields
+-- and returned type), and the names it uses (which binders must avoid)
+data Parts = Parts [(String, Term)] [(String, Term)] [(String, [(String, Term)], Term)] [String]
+
+-- Splits a `data` declaration, binding its parameters and fields to
+-- placeholders (like `genMetasGo`), to be re-bound with `bind`
+adtParts :: Term -> Maybe Parts
+adtParts term = case term of
+  Src _ val     -> adtParts val
+  Ann _ val typ -> go val typ []
+  _             -> Nothing
+  where
+    go (Src _ val) typ acc = go val typ acc
+    go val (Src _ typ) acc = go val typ acc
+    go (Lam nam bod) (All _ inp tbod) acc = go (bod (Var nam 0)) (tbod (Var nam 0)) ((nam, inp) : acc)
+    go (ADT idxs cts _) _ acc | length idxs <= length acc =
+      let args   = reverse acc
+          pars   = take (length args - length idxs) args
+          inds   = drop (length args - length idxs) args
+          ctrs   = [(cnam, flds, ret) | Ctr cnam tele <- cts, let (flds, ret) = fields tele]
+          used   = concat [map fst args, [fld | (_, flds, _) <- ctrs, (fld, _) <- flds], map (\ (cnam, _, _) -> cnam) ctrs, getDeps term]
+      in Just (Parts pars inds ctrs used)
+    go _ _ _ = Nothing
+    fields (TRet ret)         = ([], ret)
+    fields (TExt nam inp bod) = let (flds, ret) = fields (bod (Var nam 0)) in ((nam, inp) : flds, ret)
+
+-- Generation
+-- ----------
+
+-- `T/elim : ∀(params) ∀(P: ∀(indices) ∀(x: (T params indices)) *) ∀(cases) ∀(indices) ∀(x: (T params indices)) (P indices x)`
+adtElim :: String -> Parts -> Term
+adtElim name (Parts pars inds ctrs used) = bind (Ann False val typ) [] where
+  mot    = fresh used "P"
+  self   = fresh used "x"
+  cases  = [(fresh (mot : self : used) cnam, ctr) | ctr@(cnam, _, _) <- ctrs]
+  motTyp = alls inds (All self (selfType name pars inds) (\_ -> Set))
+  caseTyp (cnam, flds, ret) =
+    alls flds $ alls [(hyp fld, apps (Var mot 0) (idxs ++ [Var fld 0])) | (fld, idxs) <- recFields name pars flds] $
+    apps (Var mot 0) (retIndices pars ret ++ [Con cnam [(Just fld, Var fld 0) | (fld, _) <- flds]])
+  typ = alls pars $ All mot motTyp $ \_ -> alls [(cas, caseTyp ctr) | (cas, ctr) <- cases] $
+    alls inds $ All self (selfType name pars inds) $ \_ -> apps (Var mot 0) (vars inds ++ [Var self 0])
+  val = lams (map fst pars ++ [mot] ++ map fst cases ++ map fst inds) $ Mat
+    [ (cnam, lams (map fst flds) $ apps (Var cas 0) (vars flds ++ [rec idxs fld | (fld, idxs) <- recFields name pars flds]))
+    | (cas, (cnam, flds, _)) <- cases ]
+  rec idxs fld = apps (Ref (name ++ "/elim")) (vars pars ++ [Var mot 0] ++ vars cases ++ idxs ++ [Var fld 0])
+  hyp fld = fresh (mot : self : map fst cases ++ used) (fld ++ ".ih")
+
+-- `T/fold : ∀(params) ∀(P: ∀(indices) *) ∀(cases) ∀(indices) ∀(x: (T params indices)) (P indices)`
+adtFold :: String -> Parts -> Term
+adtFold name (Parts pars inds ctrs used) = bind (Ann False val typ) [] where
+  mot    = fresh used "P"
+  self   = fresh used "x"
+  cases  = [(fresh (mot : self : used) cnam, ctr) | ctr@(cnam, _, _) <- ctrs]
+  motTyp = alls inds Set
+  caseTyp (_, flds, ret) =
+    alls [(fld, maybe inp (apps (Var mot 0)) (lookup fld (recFields name pars flds))) | (fld, inp) <- flds] $
+    apps (Var mot 0) (retIndices pars ret)
+  typ = alls pars $ All mot motTyp $ \_ -> alls [(cas, caseTyp ctr) | (cas, ctr) <- cases] $
+    alls inds $ All self (selfType name pars inds) $ \_ -> apps (Var mot 0) (vars inds)
+  val = lams (map fst pars ++ [mot] ++ map fst cases ++ map fst inds) $ Mat
+    [ (cnam, lams (map fst flds) $ apps (Var cas 0) [maybe (Var fld 0) (\idxs -> rec idxs fld) (lookup fld (recFields name pars flds)) | (fld, _) <- flds])
+    | (cas, (cnam, flds, _)) <- cases ]
+  rec idxs fld = apps (Ref (name ++ "/fold")) (vars pars ++ [Var mot 0] ++ vars cases ++ idxs ++ [Var fld 0])
+
+-- Utils
+-- -----
+
+-- The fields of a constructor that have the declared type, with their indices
+recFields :: String -> [(String, Term)] -> [(String, Term)] -> [(String, [Term])]
+recFields name pars flds = [(fld, drop (length pars) args) | (fld, inp) <- flds, (Ref nam, args) <- [getSpine (unAnn inp)], nam == name] where
+  unAnn (Src _ val)   = unAnn val
+  unAnn (Ann _ val _) = unAnn val
+  unAnn term          = term
+
+-- The indices of the type returned by a constructor
+retIndices :: [(String, Term)] -> Term -> [Term]
+retIndices pars ret = drop (length pars) (snd (getSpine ret))
+
+selfType :: String -> [(String, Term)] -> [(String, Term)] -> Term
+selfType name pars inds = apps (Ref name) (vars (pars ++ inds))
+
+-- A name that the declaration doesn't use
+fresh :: [String] -> String -> String
+fresh used nam = head [new | new <- nam : [nam ++ show i | i <- [1 ..]], new `notElem` used]
+
+alls :: [(String, Term)] -> Term -> Term
+alls binds bod = foldr (\ (nam, inp) acc -> All nam inp (\_ -> acc)) bod binds
+
+lams :: [String] -> Term -> Term
+lams nams bod = foldr (\ nam acc -> Lam nam (\_ -> acc)) bod nams
+
+apps :: Term -> [Term] -> Term
+apps = foldl App
+
+vars :: [(String, a)] -> [Term]
+vars binds = [Var nam 0 | (nam, _) <- binds]
@@ -20,31 +20,85 @@ envFail :: Env a
 envFail = Env $ \state -> Fail state
 
 envRun :: Env a -> Book -> Res a
-envRun (Env chk) book = chk (State book IM.empty [] [])
+envRun chk book = envRunMemo chk book M.empty
+
+envRunMemo :: Env a -> Book -> Memo -> Res a
+envRunMemo (Env chk) book memo = chk (State book IM.empty [] [] memo (Stats 0 0 0 0) Nothing Nothing)
+
+-- Runs a checker, recording its trace
+envRunTrace :: Env a -> Book -> Memo -> Res a
+envRunTrace (Env chk) book memo = chk (State book IM.empty [] [] memo (Stats 0 0 0 0) (Just []) Nothing)
 
 envLog :: Info -> Env Int
-envLog log = Env $ \ (State book fill susp logs) -> Done (State book fill susp (log : logs)) 1
+envLog log = Env $ \ (State book fill susp logs memo stats trace lvls) -> Done (State book fill susp (log : logs) memo stats trace lvls) 1
 
 envSnapshot :: Env State
 envSnapshot = Env $ \state -> Done state state
 
+-- Rewinds to a snapshot, keeping the stats and trace recorded since then
 envRewind :: State -> Env Int
-envRewind state = Env $ \_ -> Done state 0
+envRewind (State book fill susp logs memo _ _ lvls) = Env $ \ (State _ _ _ _ _ stats trace _) -> Done (State book fill susp logs memo stats trace lvls) 0
 
 envSusp :: Check -> Env ()
-envSusp chk = Env $ \ (State book fill susp logs) -> Done (State book fill (susp ++ [chk]) logs) ()
+envSusp chk = Env $ \ (State book fill susp logs memo stats trace lvls) -> let stats' = statsSusp stats in stats' `seq` Done (State book fill (susp ++ [chk]) logs memo stats' trace lvls) ()
 
 envFill :: Int -> Term -> Env ()
-envFill k v = Env $ \ (State book fill susp logs) -> Done (State book (IM.insert k v fill) susp logs) ()
+envFill k v = Env $ \ (State book fill susp logs memo stats trace lvls) -> Done (State book (IM.insert k v fill) susp logs memo stats trace lvls) ()
 
 envGetFill :: Env Fill
-envGetFill = Env $ \ (State book fill susp logs) -> Done (State book fill susp logs) fill
+envGetFill = Env $ \ (State book fill susp logs memo stats trace lvls) -> Done (State book fill susp logs memo stats trace lvls) fill
 
 envGetBook :: Env Book
-envGetBook = Env $ \ (State book fill susp logs) -> Done (State book fill susp logs) book
+envGetBook = Env $ \ (State book fill susp logs memo stats trace lvls) -> Done (State book fill susp logs memo stats trace lvls) book
 
 envTakeSusp :: Env [Check]
-envTakeSusp = Env $ \ (State book fill susp logs) -> Done (State book fill [] logs) susp
+envTakeSusp = Env $ \ (State book fill susp logs memo stats trace lvls) -> Done (State book fill [] logs memo stats trace lvls) susp
+
+envMemo :: String -> Term -> Env ()
+envMemo nam typ = Env $ \ (State book fill susp logs memo stats trace lvls) -> Done (State book fill susp logs (M.insert nam typ memo) stats trace lvls) ()
+
+envGetMemo :: Env Memo
+envGetMemo = Env $ \ (State book fill susp logs memo stats trace lvls) -> Done (State book fill susp logs memo stats trace lvls) memo
+
+envCount :: (Stats -> Stats) -> Env ()
+envCount f = Env $ \ (State book fill susp logs memo stats trace lvls) -> let stats' = f stats in stats' `seq` Done (State book fill susp logs memo stats' trace lvls) ()
+
+-- Records a checker step, and the steps nested in it, when tracing
+envTrace :: String -> [String] -> Int -> Env a -> Env a
+envTrace tag terms dep (Env chk) = Env $ \ state@(State book fill susp logs memo stats trace lvls) -> case trace of
+  Nothing -> chk state
+  Just evs -> case chk (State book fill susp logs memo stats (Just (Enter tag terms dep : evs)) lvls) of
+    Done state' val -> Done (leave "done" state') val
+    Fail state'     -> Fail (leave "fail" state')
+  where leave res (State book fill susp logs memo stats trace lvls) = State book fill susp logs memo stats (fmap (Leave res :) trace) lvls
+
+-- Records that a level is at most another, when universes are on. Without
+-- them, levels are ignored, and every universe is the same as `*`.
+envBound :: Level -> Level -> Env Bool
+envBound lo hi = Env $ \ (State book fill susp logs memo stats trace lvls) -> Done (State book fill susp logs memo stats trace (fmap (Bound lo hi :) lvls)) True
+
+-- Turns universes on, from an empty set of level constraints
+envLevels :: Env ()
+envLevels = Env $ \ (State book fill susp logs memo stats trace _) -> Done (State book fill susp logs memo stats trace (Just [])) ()
+
+envGetLevels :: Env (Maybe [Bound])
+envGetLevels = Env $ \ (State book fill susp logs memo stats trace lvls) -> Done (State book fill susp logs memo stats trace lvls) lvls
+
+-- Stats
+-- -----
+
+statsEqual :: Stats -> Stats
+statsEqual (Stats eqs uns reds susp) = Stats (eqs + 1) uns reds susp
+
+statsUnify :: Stats -> Stats
+statsUnify (Stats eqs uns reds susp) = Stats eqs (uns + 1) reds susp
+
+-- Counts a reduction of a term to weak head normal form
+statsReduce :: Stats -> Stats
+statsReduce (Stats eqs uns reds susp) = Stats eqs uns (reds + 1) susp
+
+statsSusp :: Stats -> Stats
+statsSusp (Stats eqs uns reds susp) = Stats eqs uns reds (susp + 1)
 
 instance Functor Env where
   fmap f (Env chk) = Env $ \logs -> case chk logs of
@@ -3,6 +3,7 @@
 module Kind.Equal where
 
 import Control.Monad (zipWithM)
+import Data.Maybe (isNothing)
 
 import Debug.Trace
 
@@ -19,7 +20,8 @@ import qualified Data.IntMap.Strict as IM
 
 -- Checks if two terms are equal, after reduction steps
 equal :: Term -> Term -> Int -> Env Bool
-equal a b dep = debug ("== " ++ showTermGo False a dep ++ "\n.. " ++ showTermGo False b dep) $ do
+equal a b dep = envTrace "equal" [showTermGo False a dep, showTermGo False b dep] dep $ do
+  envCount statsEqual
   -- If both terms are identical, return true
   state <- envSnapshot
   is_id <- identical a b dep
@@ -30,6 +32,7 @@ equal a b dep = debug ("== " ++ showTermGo False a dep ++ "\n.. " ++ showTermGo
     envRewind state
     book <- envGetBook
     fill <- envGetFill
+    envCount (statsReduce . statsReduce)
     let aWnf = reduce book fill 2 a
     let bWnf = reduce book fill 2 b
     -- If both term wnfs are identical, return true
@@ -42,11 +45,29 @@ equal a b dep = debug ("== " ++ showTermGo False a dep ++ "\n.. " ++ showTermGo
       envRewind state
       similar aWnf bWnf dep
 
+-- Checks if a detected type fits an expected one. With universes on, they
+-- are cumulative: a type in a universe is in the ones above it too, and so
+-- are the functions returning it. Otherwise, the types must be equal.
+subsume :: Term -> Term -> Int -> Env Bool
+subsume a b dep = do
+  levels <- envGetLevels
+  book   <- envGetBook
+  fill   <- envGetFill
+  envCount (statsReduce . statsReduce)
+  case (levels, reduce book fill 2 a, reduce book fill 2 b) of
+    (Just _, Uni aLvl, Uni bLvl) -> do
+      envBound bLvl aLvl
+    (Just _, All aNam aInp aBod, All bNam bInp bBod) -> do
+      eInp <- equal aInp bInp dep
+      eBod <- subsume (aBod (Var aNam dep)) (bBod (Var bNam dep)) (dep + 1)
+      return (eInp && eBod)
+    _ -> do
+      equal a b dep
+
 -- Checks if two terms are already syntactically identical
 identical :: Term -> Term -> Int -> Env Bool
-identical a b dep = do
-  fill <- envGetFill
-  debug ("ID " ++ showTermGo False a dep ++ "\n.. " ++ showTermGo False b dep ++ "\n" ++ (unlines $ map (\(k,v) -> "~" ++ show k ++ " = " ++ showTermGo False v dep) $ IM.toList fill)) $ go a b dep
+identical a b dep =
+  envTrace "identical" [showTermGo False a dep, showTermGo False b dep] dep $ go a b dep
  where
   go (All aNam aInp aBod) (All bNam bInp bBod) dep = do
     iInp <- identical aInp bInp dep
@@ -84,6 +105,14 @@ identical a b dep = do
     identical a (bBod bVal) dep
   go Set Set dep =
     return True
+  go (Uni aLvl) (Uni bLvl) dep = do
+    iLo <- envBound aLvl bLvl
+    iHi <- envBound bLvl aLvl
+    return (iLo && iHi)
+  go Set (Uni bLvl) dep =
+    isNothing <$> envGetLevels
+  go (Uni aLvl) Set dep =
+    isNothing <$> envGetLevels
   go (Ann chk aVal aTyp) b dep =
     identical aVal b dep
   go a (Ann chk bVal bTyp) dep =
@@ -264,6 +293,7 @@ similar a b dep = go a b dep where
 -- If possible, solves a (?X x y z ...) = K problem, generating a subst.
 unify :: Int -> [Term] -> Term -> Int -> Env Bool
 unify uid spn b dep = do
+  envCount statsUnify
   book <- envGetBook
   fill <- envGetFill
 
@@ -276,10 +306,10 @@ unify uid spn b dep = do
   -- is the solution not recursive?
   let no_loops = not $ occur book fill uid b dep
 
-  debug ("unify: " ++ show uid ++ " " ++ showTermGo False b dep ++ " | " ++ show solved ++ " " ++ show solvable ++ " " ++ show no_loops) $ do
+  envTrace "unify" ["?" ++ show uid, showTermGo False b dep] dep $ do
     if not solved && solvable && no_loops then do
       let solution = solve book fill uid spn b
-      debug ("solve: " ++ show uid ++ " " ++ showTermGo False solution dep ++ " | spn: " ++ show (map (\t -> showTermGo False t dep) spn)) $ envFill uid solution
+      envTrace "solve" ["?" ++ show uid, showTermGo False solution dep] dep $ envFill uid solution
       return True
 
     -- Otherwise, return true iff both are identical metavars
@@ -440,6 +470,8 @@ same a (Use bNam bVal bBod) dep =
   same a (bBod bVal) dep
 same Set Set dep =
   True
+same (Uni aLvl) (Uni bLvl) dep =
+  aLvl == bLvl
 same (Ann chk aVal aTyp) b dep =
   same aVal b dep
 same a (Ann chk bVal bTyp) dep =
@@ -545,6 +577,7 @@ subst lvl neo term = go term where
   go (Log msg nxt)     = Log (go msg) (go nxt)
   go (Hol nam ctx)     = Hol nam (map go ctx)
   go Set               = Set
+  go (Uni lvl)         = Uni lvl
   go U64               = U64
   go F64               = F64
   go (Num n)           = Num n
@@ -585,6 +618,7 @@ replace old neo term dep = if same old term dep then neo else go term where
   go (Log msg nxt)      = Log (replace old neo msg dep) (replace old neo nxt dep)
   go (Hol nam ctx)      = Hol nam (map (\x -> replace old neo x (dep+1)) ctx)
   go Set                = Set
+  go (Uni lvl)          = Uni lvl
   go U64                = U64
   go F64                = F64
   go (Num n)            = Num n
@@ -609,4 +643,6 @@ incompatible (Con aNam aArg) (Con bNam bArg) dep | aNam /= bNam = True
 incompatible (Con aNam aArg) (Con bNam bArg) dep | otherwise    = length aArg == length bArg && any (\(a,b) -> incompatible a b dep) (zip (map snd aArg) (map snd bArg))
 incompatible (Src aSrc aVal) b               dep                = incompatible aVal b dep
 incompatible a               (Src bSrc bVal) dep                = incompatible a bVal dep
+incompatible (Ann _ aVal _)  b               dep                = incompatible aVal b dep
+incompatible a               (Ann _ bVal _)  dep                = incompatible a bVal dep
 incompatible _               _               _                  = False
@@ -0,0 +1,164 @@
+-- //./Type.hs//
+
+module Kind.Format where
+
+import Data.Char (isSpace)
+import Data.List (isPrefixOf, dropWhileEnd)
+
+-- Source Formatter
+-- ----------------
+
+-- The parser desugars as it goes, so the formatter works on tokens instead of
+-- terms. It only normalizes whitespace: tokens are re-printed exactly as
+-- written (keeping comments, `use` aliases, equations, `data` declarations
+-- and every sugar), with canonical spacing and indentation between them. Line
+-- breaks are kept where they were written, so it isn't a canonical layout:
+-- two layouts of the same term can still format differently.
+
+data FmtTok
+  = FWord String  -- names and numbers
+  | FSym  String  -- operators and punctuation
+  | FOpen Char    -- '(', '[' or '{'
+  | FClose Char   -- ')', ']' or '}'
+  | FText String  -- string and char literals, as written
+  | FComm String  -- line comments, as written
+  | FBreak        -- line breaks
+  deriving (Eq)
+
+-- A source line: its original indentation and its tokens, with flags telling
+-- which tokens were preceded by whitespace.
+type FmtLine = (Int, [(Bool, FmtTok)])
+
+-- An open bracket: the indentation of its line, and the original
+-- indentation of the first line inside it.
+type FmtBlock = (Int, Maybe Int)
+
+-- Formats a source file
+formatSource :: String -> String
+formatSource src = unlines $ map (dropWhileEnd isSpace) $ formatBlanks $ formatIndent [(-2, Just 0)] $ formatLines $ formatLex src
+
+-- Checks if a source file is already formatted
+isFormatted :: String -> Bool
+isFormatted src = formatSource src == src
+
+-- Lexer
+-- -----
+
+formatSymbols :: [String]
+formatSymbols = ["->", "::", "==", ";;", ":=", "<=", ">=", "!=", "<<", ">>"]
+
+formatNameChar :: Char -> Bool
+formatNameChar c = c `elem` "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789/_.-$"
+
+-- Splits the source into tokens, each with the count of whitespace before it
+formatLex :: String -> [(Int, FmtTok)]
+formatLex = go 0 where
+  go n ""                       = []
+  go n ('\n' : cs)              = (n, FBreak) : go 0 cs
+  go n (c : cs) | isSpace c     = go (n + 1) cs
+  go n str@('/' : '/' : _)      = let (com, rest) = break (== '\n') str in (n, FComm (dropWhileEnd isSpace com)) : go 0 rest
+  go n str@('"' : _)            = let (txt, rest) = formatLexText '"' str in (n, FText txt) : go 0 rest
+  go n str@('\'' : _)           = let (txt, rest) = formatLexText '\'' str in (n, FText txt) : go 0 rest
+  go n (c : cs) | c `elem` "([{" = (n, FOpen c) : go 0 cs
+  go n (c : cs) | c `elem` ")]}" = (n, FClose c) : go 0 cs
+  go n str = case filter (`isPrefixOf` str) formatSymbols of
+    (sym : _) -> (n, FSym sym) : go 0 (drop (length sym) str)
+    [] | formatNameChar (head str) -> let (wrd, rest) = span formatNameChar str in (n, FWord wrd) : go 0 rest
+    []        -> (n, FSym [head str]) : go 0 (tail str)
+
+-- Takes a quoted literal, keeping its escapes as written
+formatLexText :: Char -> String -> (String, String)
+formatLexText quote (open : str) = let (txt, rest) = go str in (open : txt, rest) where
+  go ""                      = ("", "")
+  go ('\\' : c : cs)         = let (txt, rest) = go cs in ('\\' : c : txt, rest)
+  go (c : cs) | c == quote   = ([c], cs)
+  go (c : cs)                = let (txt, rest) = go cs in (c : txt, rest)
+formatLexText quote "" = ("", "")
+
+-- Splits the names of a source file into its `use long as short` aliases and
+-- the names in its body
+sourceUses :: String -> ([(String, String)], [String])
+sourceUses code = header [tok | (_, tok) <- formatLex code, isWord tok] where
+  header (FWord "use" : FWord long : FWord "as" : FWord short : rest) = let (us, bd) = header rest in ((long, short) : us, bd)
+  header rest = ([], [name | FWord name <- rest])
+  isWord (FWord _) = True
+  isWord _         = False
+
+-- Groups tokens into lines
+formatLines :: [(Int, FmtTok)] -> [FmtLine]
+formatLines toks = case break ((== FBreak) . snd) toks of
+  (line, [])       -> [toLine line]
+  (line, _ : rest) -> toLine line : formatLines rest
+  where
+    toLine []                = (0, [])
+    toLine ((ind, tok) : ts) = (ind, (False, tok) : [(n > 0, t) | (n, t) <- ts])
+
+-- Layout
+-- ------
+
+-- Indents lines by bracket nesting. Inside a bracket, lines are indented one
+-- step past the line that opened it; lines that were indented past the first
+-- line of their block are kept as a hanging continuation, one step further.
+formatIndent :: [FmtBlock] -> [FmtLine] -> [String]
+formatIndent stack []                      = []
+formatIndent stack ((ind, []) : lines)     = "" : formatIndent stack lines
+formatIndent stack ((ind, toks) : lines)   =
+  let closes           = length (takeWhile (isClose . snd) toks)
+      popped           = min closes (length stack - 1)
+      (stack', indent) = if popped > 0
+        then (drop popped stack, fst (stack !! (popped - 1)))
+        else place stack
+      stack''          = foldl (step indent) stack' (drop popped toks)
+  in (replicate indent ' ' ++ formatTokens toks) : formatIndent stack'' lines
+  where
+    place ((out, first) : outer)
+      | null outer && isTopCont (snd (head toks)) = ((out, first) : outer, 0)
+      | otherwise = case first of
+        Nothing | isComm (snd (head toks)) -> ((out, first) : outer, out + 2)
+        Nothing                            -> ((out, Just ind) : outer, out + 2)
+        Just ini                           -> ((out, first) : outer, out + 2 + (if ind > ini then 2 else 0))
+    place [] = ([], 0)
+    step indent stk (_, FOpen _)  = (indent, Nothing) : stk
+    step indent stk (_, FClose _) = if length stk > 1 then tail stk else stk
+    step indent stk _             = stk
+    isClose (FClose _)     = True
+    isClose _              = False
+    isComm (FComm _)       = True
+    isComm _               = False
+    -- Top-level lines that continue a definition's header
+    isTopCont (FSym s)     = s `elem` [":", "=", "|"]
+    isTopCont (FWord w)    = all (== '.') w
+    isTopCont _            = False
+
+-- Joins the tokens of a line, normalizing the whitespace between them
+formatTokens :: [(Bool, FmtTok)] -> String
+formatTokens toks = concat $ zipWith join (Nothing : map (Just . snd) toks) toks where
+  join Nothing        (_, tok)   = showTok tok
+  join (Just prv) (spc, tok)
+    | spc || spaced prv tok      = ' ' : showTok tok
+    | otherwise                  = showTok tok
+  -- Spaces that are safe to add, since the tokens around them can't merge
+  spaced (FSym s) tok | s `elem` [",", ":"] = not (isCloseTok tok)
+  spaced (FSym s) tok | s `elem` ["=", ":=", "->"] = not (isSymTok tok)
+  spaced prv (FSym s) | s `elem` ["=", ":=", "->"] = not (isSymTok prv)
+  spaced _ _                     = False
+  isCloseTok (FClose _) = True
+  isCloseTok _          = False
+  isSymTok (FSym _)     = True
+  isSymTok _            = False
+
+-- Collapses runs of blank lines, and drops the ones at the edges of the file
+formatBlanks :: [String] -> [String]
+formatBlanks = dropWhileEnd null . dropWhile null . go where
+  go ("" : "" : lines) = go ("" : lines)
+  go (line : lines)    = line : go lines
+  go []                = []
+
+showTok :: FmtTok -> String
+showTok (FWord w)  = w
+showTok (FSym s)   = s
+showTok (FOpen c)  = [c]
+showTok (FClose c) = [c]
+showTok (FText t)  = t
+showTok (FComm c)  = c
+showTok FBreak     = "\n"
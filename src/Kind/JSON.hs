@@ -0,0 +1,151 @@
+module Kind.JSON where
+
+import Data.Char (chr, ord)
+import Data.List (intercalate)
+import Kind.Type
+import Numeric (showHex, readHex)
+import qualified Data.Map.Strict as M
+import qualified Text.Parsec as P
+
+-- Command-line options, like `--format json` (flags map to "")
+type Opts = M.Map String String
+
+-- Checks if output should be JSON instead of ANSI text
+isJSON :: Opts -> Bool
+isJSON opts = M.lookup "--format" opts == Just "json"
+
+-- JSON Values
+-- -----------
+
+data JSON
+  = JNull
+  | JBool Bool
+  | JNum Double
+  | JStr String
+  | JArr [JSON]
+  | JObj [(String, JSON)]
+
+-- Converts a source location into a range of 1-based lines and columns
+codToJSON :: Cod -> JSON
+codToJSON (Cod (Loc _ iniLin iniCol) (Loc _ endLin endCol)) = JObj
+  [ ("start", JObj [("line", JNum (fromIntegral iniLin)), ("column", JNum (fromIntegral iniCol))])
+  , ("end",   JObj [("line", JNum (fromIntegral endLin)), ("column", JNum (fromIntegral endCol))])
+  ]
+
+-- Stringification
+-- ---------------
+
+showJSON :: JSON -> String
+showJSON JNull      = "null"
+showJSON (JBool b)  = if b then "true" else "false"
+showJSON (JNum n)   = if n == fromIntegral (round n :: Integer) then show (round n :: Integer) else show n
+showJSON (JStr s)   = showJSONStr s
+showJSON (JArr xs)  = concat ["[", intercalate "," (map showJSON xs), "]"]
+showJSON (JObj kvs) = concat ["{", intercalate "," (map (\ (k, v) -> showJSONStr k ++ ":" ++ showJSON v) kvs), "}"]
+
+showJSONStr :: String -> String
+showJSONStr str = concat ["\"", concatMap escape str, "\""] where
+  escape '"'  = "\\\""
+  escape '\\' = "\\\\"
+  escape '\n' = "\\n"
+  escape '\r' = "\\r"
+  escape '\t' = "\\t"
+  escape c | c < ' '   = "\\u" ++ reverse (take 4 (reverse (showHex (ord c) "") ++ repeat '0'))
+           | otherwise = [c]
+
+-- Parsing
+-- -------
+
+type JSONParser a = P.Parsec String () a
+
+parseJSON :: String -> Maybe JSON
+parseJSON input = case P.parse (P.spaces *> parseValue <* P.eof) "json" input of
+  Left err   -> Nothing
+  Right json -> Just json
+
+parseValue :: JSONParser JSON
+parseValue = P.choice
+  [ P.try (P.string "null")  >> return JNull
+  , P.try (P.string "true")  >> return (JBool True)
+  , P.try (P.string "false") >> return (JBool False)
+  , JNum <$> parseNumber
+  , JStr <$> parseString
+  , JArr <$> parseList '[' ']' parseValue
+  , JObj <$> parseList '{' '}' parseField
+  ] <* P.spaces
+
+parseField :: JSONParser (String, JSON)
+parseField = do
+  key <- parseString
+  P.spaces
+  P.char ':'
+  P.spaces
+  val <- parseValue
+  return (key, val)
+
+parseList :: Char -> Char -> JSONParser a -> JSONParser [a]
+parseList open close item = do
+  P.char open
+  P.spaces
+  items <- P.sepBy (item <* P.spaces) (P.char ',' >> P.spaces)
+  P.char close
+  return items
+
+parseNumber :: JSONParser Double
+parseNumber = do
+  sign <- P.option "" (P.string "-")
+  int  <- P.many1 P.digit
+  frac <- P.option "" (P.char '.' >> (('.' :) <$> P.many1 P.digit))
+  expo <- P.option "" $ do
+    P.oneOf "eE"
+    expSign <- P.option "" (P.string "-" P.<|> (P.string "+" >> return ""))
+    expDigits <- P.many1 P.digit
+    return ("e" ++ expSign ++ expDigits)
+  let frac' = if null frac && not (null expo) then ".0" else frac
+  return $ read (sign ++ int ++ frac' ++ expo)
+
+parseString :: JSONParser String
+parseString = do
+  P.char '"'
+  str <- P.many parseChar
+  P.char '"'
+  return (joinSurrogates str)
+  where
+    parseChar = P.noneOf "\"\\" P.<|> (P.char '\\' >> parseEscape)
+    parseEscape = P.choice
+      [ P.char '"'  >> return '"'
+      , P.char '\\' >> return '\\'
+      , P.char '/'  >> return '/'
+      , P.char 'b'  >> return '\b'
+      , P.char 'f'  >> return '\f'
+      , P.char 'n'  >> return '\n'
+      , P.char 'r'  >> return '\r'
+      , P.char 't'  >> return '\t'
+      , P.char 'u'  >> (chr . fst . head . readHex) <$> P.count 4 P.hexDigit
+      ]
+    joinSurrogates (hi : lo : rest)
+      | ord hi >= 0xD800 && ord hi < 0xDC00 && ord lo >= 0xDC00 && ord lo < 0xE000
+      = chr (0x10000 + (ord hi - 0xD800) * 0x400 + (ord lo - 0xDC00)) : joinSurrogates rest
+    joinSurrogates (c : rest) = c : joinSurrogates rest
+    joinSurrogates [] = []
+
+-- Accessors
+-- ---------
+
+-- Gets the value at a path of object keys
+jsonGet :: [String] -> JSON -> Maybe JSON
+jsonGet []           json       = Just json
+jsonGet (key : keys) (JObj kvs) = lookup key kvs >>= jsonGet keys
+jsonGet _            _          = Nothing
+
+jsonStr :: JSON -> Maybe String
+jsonStr (JStr str) = Just str
+jsonStr _          = Nothing
+
+jsonInt :: JSON -> Maybe Int
+jsonInt (JNum num) = Just (round num)
+jsonInt _          = Nothing
+
+jsonArr :: JSON -> Maybe [JSON]
+jsonArr (JArr arr) = Just arr
+jsonArr _          = Nothing
@@ -0,0 +1,345 @@
+-- //./Type.hs//
+
+-- Language Server Protocol (LSP) server, speaking JSON-RPC over stdio.
+-- Serves the Src spans recorded by the parser: checker errors become
+-- diagnostics, the annotated terms answer hovers, refs jump to their
+-- definitions, and hole goals are shown as inlay hints.
+
+module Kind.LSP where
+
+import Control.Exception (SomeException, displayException, evaluate, try)
+import Control.Monad (forM, replicateM)
+import Data.Bits ((.&.), (.|.), shiftL, shiftR)
+import Data.Char (chr, ord, toLower, isHexDigit)
+import Data.List (isPrefixOf, minimumBy, findIndex)
+import Data.Maybe (fromMaybe, mapMaybe, listToMaybe)
+import Data.Ord (comparing)
+import GHC.IO.Handle (hDuplicate, hDuplicateTo)
+import Kind.Check
+import Kind.Env
+import Kind.JSON
+import Kind.Load
+import Kind.Parse
+import Kind.Reduce
+import Kind.Show
+import Kind.Type
+import Kind.Util
+import Numeric (readHex)
+import System.Directory (doesFileExist)
+import System.IO
+import Text.Parsec (sourceLine, sourceColumn)
+import Text.Parsec.Error (errorPos, ParseError)
+import Text.Read (readMaybe)
+import qualified Data.Map.Strict as M
+import qualified Data.Set as S
+
+-- An open document: its path, its text, its parse (or deriving) errors, its
+-- definitions, the loaded context, and, per definition, the checker state (or
+-- why the checker crashed) and the annotated term (if any)
+data Doc = Doc FilePath String [ParseError] Book FileCtx [(String, Either String State, Maybe (Term, Fill))]
+
+-- Server
+-- ------
+
+-- Runs the server until the client asks it to exit
+runLSP :: Roots -> IO (Either String ())
+runLSP roots = do
+  -- Messages go to the real stdout; anything else printed goes to stderr
+  out <- hDuplicate stdout
+  hDuplicateTo stderr stdout
+  hSetBinaryMode stdin True
+  hSetBinaryMode out True
+  loop out M.empty
+  where
+    loop out docs = do
+      msg <- readMessage stdin
+      case msg of
+        Nothing  -> return $ Right ()
+        Just msg -> do
+          docs' <- handleMessage roots out docs msg
+          case docs' of
+            Nothing    -> return $ Right ()
+            Just docs' -> loop out docs'
+
+-- Handles a request or notification, returning Nothing on exit
+handleMessage :: Roots -> Handle -> M.Map String Doc -> JSON -> IO (Maybe (M.Map String Doc))
+handleMessage roots out docs msg = do
+  let method = jsonGet ["method"] msg >>= jsonStr
+  let ident  = jsonGet ["id"] msg
+  let uri    = fromMaybe "" (jsonGet ["params", "textDocument", "uri"] msg >>= jsonStr)
+  let pos    = do
+        lin <- jsonGet ["params", "position", "line"] msg >>= jsonInt
+        col <- jsonGet ["params", "position", "character"] msg >>= jsonInt
+        return (lin + 1, col + 1)
+  case method of
+    Just "initialize" -> do
+      respond ident capabilities
+      return $ Just docs
+    Just "shutdown" -> do
+      respond ident JNull
+      return $ Just docs
+    Just "exit" -> do
+      return Nothing
+    Just "textDocument/didOpen" -> do
+      let text = fromMaybe "" (jsonGet ["params", "textDocument", "text"] msg >>= jsonStr)
+      update uri text
+    Just "textDocument/didChange" -> do
+      let changes = fromMaybe [] (jsonGet ["params", "contentChanges"] msg >>= jsonArr)
+      case mapMaybe (\change -> jsonGet ["text"] change >>= jsonStr) changes of
+        []      -> return $ Just docs
+        changes -> update uri (last changes)
+    Just "textDocument/didSave" -> do
+      case M.lookup uri docs of
+        Just (Doc _ text _ _ _ _) -> update uri text
+        Nothing                 -> return $ Just docs
+    Just "textDocument/didClose" -> do
+      publish uri []
+      return $ Just (M.delete uri docs)
+    Just "textDocument/hover" -> do
+      respond ident $ fromMaybe JNull $ do
+        doc <- M.lookup uri docs
+        pos <- pos
+        hover doc pos
+      return $ Just docs
+    Just "textDocument/definition" -> do
+      result <- case (M.lookup uri docs, pos) of
+        (Just doc, Just pos) -> definition doc pos
+        _                    -> return JNull
+      respond ident result
+      return $ Just docs
+    Just "textDocument/inlayHint" -> do
+      respond ident $ JArr $ maybe [] inlayHints (M.lookup uri docs)
+      return $ Just docs
+    Just _ | Just ident <- ident -> do
+      writeMessage out $ JObj [("jsonrpc", JStr "2.0"), ("id", ident), ("error", JObj [("code", JNum (-32601)), ("message", JStr "Method not found")])]
+      return $ Just docs
+    _ -> do
+      return $ Just docs
+  where
+    respond ident result = case ident of
+      Just ident -> writeMessage out $ JObj [("jsonrpc", JStr "2.0"), ("id", ident), ("result", result)]
+      Nothing    -> return ()
+    publish uri diags =
+      writeMessage out $ JObj [("jsonrpc", JStr "2.0"), ("method", JStr "textDocument/publishDiagnostics"), ("params", JObj [("uri", JStr uri), ("diagnostics", JArr diags)])]
+    update uri text = do
+      doc <- checkDoc roots (uriToPath uri) text
+      publish uri (diagnostics doc)
+      return $ Just (M.insert uri doc docs)
+
+capabilities :: JSON
+capabilities = JObj
+  [ ("capabilities", JObj
+    [ ("textDocumentSync", JNum 1)
+    , ("hoverProvider", JBool True)
+    , ("definitionProvider", JBool True)
+    , ("inlayHintProvider", JBool True)
+    ])
+  , ("serverInfo", JObj [("name", JStr "kind")])
+  ]
+
+-- Checking
+-- --------
+
+-- Parses a document, loads its dependencies and checks each of its definitions
+checkDoc :: Roots -> FilePath -> String -> IO Doc
+checkDoc roots path text = do
+  (fileCtx@(book, defs, _, _), fails) <- loadCode roots M.empty path text
+  let errs  = [err | (file, _, err) <- fails, file == path]
+  let book0 = M.restrictKeys book (S.fromList (M.findWithDefault [] path defs))
+  -- Checks and annotates in one run, forcing it here, so that a crash of the
+  -- checker becomes a diagnostic instead of taking the server down
+  results <- forM (M.toList book0) $ \ (name, term) -> do
+    let (state, annotated) = case envRun (doCheckAnnotate term) book of
+          Done _ (state, result) -> (state, Just result)
+          Fail state             -> (state, Nothing)
+    let forced = forceState state + maybe 0 (\ (ann, _) -> length (showTerm ann)) annotated
+    done <- try (evaluate forced)
+    return $ case done of
+      Left err -> (name, Left (displayException (err :: SomeException)), Nothing)
+      Right _  -> (name, Right state, annotated)
+  return $ Doc path text errs book0 fileCtx results
+
+-- Converts parse errors and checker errors into diagnostics
+diagnostics :: Doc -> [JSON]
+diagnostics (Doc path text errs book0 _ results) = map failed errs ++ concatMap go results
+  where
+    failed err =
+      let pos = errorPos err
+          lin = sourceLine pos - 1
+          col = sourceColumn pos - 1
+      in diagnostic (jsonRange (lin, col) (lin, col + 1)) 1 ("expected: " ++ extractExpectedTokens err)
+    go (name, Left err, _) = [diagnostic (defRange text name) 1 ("checker crashed: " ++ err)]
+    go (name, Right (State book fill _ logs _ _ _ _), _) = flip mapMaybe (reverse logs) $ \log -> case log of
+      Error src exp det bad dep ->
+        let exp' = showTermGo True (normal book fill 0 exp dep) dep
+            det' = showTermGo True (normal book fill 0 det dep) dep
+            bad' = showTermGo True (normal book fill 0 bad dep) dep
+            msg  = concat ["expected: ", exp', "\ndetected: ", det', "\norigin: ", bad']
+        in Just $ diagnostic (maybe (defRange text name) id (src >>= codRange path)) 1 msg
+      Warning src msg bad dep ->
+        let bad' = showTermGo True (normal book fill 0 bad dep) dep
+        in Just $ diagnostic (maybe (defRange text name) id (src >>= codRange path)) 2 (concat ["warning: ", msg, "\norigin: ", bad'])
+      Vague nam ->
+        Just $ diagnostic (defRange text name) 2 ("vague: _" ++ nam)
+      _ -> Nothing
+
+diagnostic :: JSON -> Int -> String -> JSON
+diagnostic range severity message = JObj
+  [ ("range", range)
+  , ("severity", JNum (fromIntegral severity))
+  , ("source", JStr "kind")
+  , ("message", JStr message)
+  ]
+
+-- Queries
+-- -------
+
+-- Shows the type of the innermost annotated subterm under the cursor
+hover :: Doc -> (Int, Int) -> Maybe JSON
+hover (Doc path _ _ _ (book, _, _, _) results) pos = do
+  let spans = [ (cod, val, typ, fill, dep)
+              | (_, _, Just (term, fill)) <- results
+              , (cod@(Cod (Loc file _ _) _), Ann _ val typ, dep) <- getSrcs term 0
+              , file == path
+              , codHas cod pos ]
+  (cod, val, typ, fill, dep) <- innermost (\ (cod, _, _, _, _) -> cod) spans
+  let typ' = showTermGo True (normal book fill 0 typ dep) dep
+  let txt  = case val of
+        Ref nam -> nam ++ " : " ++ typ'
+        _       -> typ'
+  return $ JObj
+    [ ("contents", JObj [("kind", JStr "markdown"), ("value", JStr ("```\n" ++ txt ++ "\n```"))])
+    , ("range", fromMaybe JNull (codRange path cod))
+    ]
+
+-- Finds the definition of the reference under the cursor
+definition :: Doc -> (Int, Int) -> IO JSON
+definition (Doc path text _ book0 (_, defs, _, _) _) pos = do
+  let refs = [ (cod, nam)
+             | term <- M.elems book0
+             , (cod@(Cod (Loc file _ _) _), Ref nam, _) <- getSrcs term 0
+             , file == path
+             , codHas cod pos ]
+  case innermost fst refs of
+    Nothing -> return JNull
+    Just (_, nam) -> do
+      case [file | (file, names) <- M.toList defs, nam `elem` names] of
+        [] -> return JNull
+        (file : _) -> do
+          exists <- doesFileExist file
+          content <- if file == path then return text else if exists then readFile file else return ""
+          return $ JObj [("uri", JStr (pathToUri file)), ("range", defRange content nam)]
+
+-- Shows the goal of each hole as an inlay hint after it
+inlayHints :: Doc -> [JSON]
+inlayHints (Doc path _ _ book0 _ results) = do
+  (_, Right (State book fill _ logs _ _ _ _), _) <- results
+  Found _ nam typ ctx dep <- reverse logs
+  Cod _ (Loc _ endLin endCol) <- take 1 (holeSpans nam)
+  let typ' = showTermGo True (normal book fill 0 typ dep) dep
+  return $ JObj
+    [ ("position", jsonPos (endLin - 1, endCol - 1))
+    , ("label", JStr (": " ++ typ'))
+    , ("kind", JNum 1)
+    , ("paddingLeft", JBool True)
+    ]
+  where
+    holeSpans nam =
+      [ cod
+      | term <- M.elems book0
+      , (cod@(Cod (Loc file _ _) _), Hol hol _, _) <- getSrcs term 0
+      , file == path
+      , hol == nam ]
+
+-- Utils
+-- -----
+
+-- Picks the entry with the smallest span
+innermost :: (a -> Cod) -> [a] -> Maybe a
+innermost getCod [] = Nothing
+innermost getCod xs = Just $ minimumBy (comparing (size . getCod)) xs where
+  size (Cod (Loc _ iniLin iniCol) (Loc _ endLin endCol)) = (endLin - iniLin, endCol - iniCol)
+
+-- Converts a source location in the given file into an LSP range
+codRange :: FilePath -> Cod -> Maybe JSON
+codRange path (Cod (Loc file iniLin iniCol) (Loc _ endLin endCol))
+  | file == path = Just $ jsonRange (iniLin - 1, iniCol - 1) (endLin - 1, endCol - 1)
+  | otherwise    = Nothing
+
+-- Gets the range of the line where a definition starts
+defRange :: String -> String -> JSON
+defRange text name =
+  let isDef line = any (`isPrefixOf` line) [name ++ " ", name ++ ":", "data " ++ name, "#" ++ name]
+      lin = fromMaybe 0 (findIndex isDef (lines text))
+  in jsonRange (lin, 0) (lin, length name)
+
+jsonRange :: (Int, Int) -> (Int, Int) -> JSON
+jsonRange ini end = JObj [("start", jsonPos ini), ("end", jsonPos end)]
+
+jsonPos :: (Int, Int) -> JSON
+jsonPos (lin, col) = JObj [("line", JNum (fromIntegral lin)), ("character", JNum (fromIntegral col))]
+
+uriToPath :: String -> FilePath
+uriToPath uri = decode (fromMaybe uri (stripScheme uri)) where
+  stripScheme uri
+    | "file://" `isPrefixOf` uri = Just (drop 7 uri)
+    | otherwise                  = Nothing
+  decode ('%' : a : b : rest) | isHexDigit a && isHexDigit b = chr (fst (head (readHex [a, b]))) : decode rest
+  decode (c : rest) = c : decode rest
+  decode [] = []
+
+pathToUri :: FilePath -> String
+pathToUri path = "file://" ++ concatMap encode path where
+  encode ' ' = "%20"
+  encode c   = [c]
+
+-- Transport
+-- ---------
+
+-- Reads a message framed by a Content-Length header
+readMessage :: Handle -> IO (Maybe JSON)
+readMessage handle = do
+  eof <- hIsEOF handle
+  if eof then return Nothing else do
+    headers <- readHeaders
+    case lookup "content-length" headers >>= readMaybe of
+      Nothing  -> return Nothing
+      Just len -> do
+        body <- replicateM len (hGetChar handle)
+        return $ Just $ fromMaybe JNull (parseJSON (decodeUTF8 body))
+  where
+    readHeaders = do
+      line <- filter (/= '\r') <$> hGetLine handle
+      if null line then return [] else do
+        rest <- readHeaders
+        let (key, val) = break (== ':') line
+        return ((map toLower key, dropWhile (== ' ') (drop 1 val)) : rest)
+
+-- Writes a message framed by a Content-Length header
+writeMessage :: Handle -> JSON -> IO ()
+writeMessage handle msg = do
+  let body = encodeUTF8 (showJSON msg)
+  hPutStr handle ("Content-Length: " ++ show (length body) ++ "\r\n\r\n" ++ body)
+  hFlush handle
+
+-- Encodes a string as UTF-8, one byte per Char
+encodeUTF8 :: String -> String
+encodeUTF8 = concatMap (map chr . go . ord) where
+  go c
+    | c < 0x80    = [c]
+    | c < 0x800   = [0xC0 .|. shiftR c 6, 0x80 .|. c .&. 0x3F]
+    | c < 0x10000 = [0xE0 .|. shiftR c 12, 0x80 .|. shiftR c 6 .&. 0x3F, 0x80 .|. c .&. 0x3F]
+    | otherwise   = [0xF0 .|. shiftR c 18, 0x80 .|. shiftR c 12 .&. 0x3F, 0x80 .|. shiftR c 6 .&. 0x3F, 0x80 .|. c .&. 0x3F]
+
+-- Decodes a string of UTF-8 bytes, one byte per Char
+decodeUTF8 :: String -> String
+decodeUTF8 [] = []
+decodeUTF8 (a : rest)
+  | o < 0x80, _ <- rest               = a : decodeUTF8 rest
+  | o < 0xE0, (b : rest') <- rest     = chr (shiftL (o .&. 0x1F) 6 .|. cont b) : decodeUTF8 rest'
+  | o < 0xF0, (b : c : rest') <- rest = chr (shiftL (o .&. 0x0F) 12 .|. shiftL (cont b) 6 .|. cont c) : decodeUTF8 rest'
+  | (b : c : d : rest') <- rest       = chr (shiftL (o .&. 0x07) 18 .|. shiftL (cont b) 12 .|. shiftL (cont c) 6 .|. cont d) : decodeUTF8 rest'
+  | otherwise                         = []
+  where
+    o      = ord a
+    cont x = ord x .&. 0x3F
@@ -0,0 +1,192 @@
+-- //./Type.hs//
+
+module Kind.Load where
+
+import Control.Applicative ((<|>))
+import Control.Monad (forM, foldM, filterM)
+import Data.List (isPrefixOf, isSuffixOf, maximumBy, nub)
+import Data.Maybe (isJust)
+import Data.Ord (comparing)
+import Kind.Derive
+import Kind.Elim
+import Kind.Parse
+import Kind.Type
+import Kind.Util
+import System.Directory (doesDirectoryExist, doesFileExist, getDirectoryContents)
+import System.FilePath ((</>), takeFileName)
+import Text.Parsec.Error (newErrorMessage, Message(..))
+import qualified Data.Map.Strict as M
+import qualified Data.Set as S
+import qualified Text.Parsec as P
+
+-- The loaded book, the definitions and the dependencies of each file, and the
+-- definitions marked `#partial`
+type FileCtx = (Book, M.Map FilePath [String], M.Map FilePath [String], S.Set String)
+
+-- Book roots, searched in order, with the prefix their names are mounted
+-- under: "" for our own roots, the dependency's name for a dependency's
+type Roots = [(String, FilePath)]
+
+-- A file that failed to parse: its path, its source, and the error
+type ParseFail = (FilePath, String, P.ParseError)
+
+-- Loader
+-- ------
+
+-- Loads a name and all its dependencies recursively
+loadName :: Roots -> Book -> String -> IO (FileCtx, [ParseFail])
+loadName roots book name = do
+  if M.member name book
+    then do
+      return ((book, M.empty, M.empty, S.empty), [])
+    else do
+      filePath <- findDefFile roots name
+      case (filePath, elimBase name <|> deriveBase name) of
+        (Just filePath, _) -> loadFile roots book filePath
+        (Nothing, Just base) | not (M.member base book) -> loadName roots book base
+        _                  -> return ((book, M.empty, M.empty, S.empty), [])
+
+-- Finds the file that defines a name, trying each book root in order
+findDefFile :: Roots -> String -> IO (Maybe FilePath)
+findDefFile [] name = return Nothing
+findDefFile ((prefix, bookPath) : roots) name = case unmountName prefix name of
+  Nothing   -> findDefFile roots name
+  Just local -> do
+    let dirPath = bookPath </> local
+    isDir <- doesDirectoryExist dirPath
+    let filePath = if isDir then dirPath </> takeFileName local ++ ".kind" else bookPath </> local ++ ".kind"
+    fileExists <- doesFileExist filePath
+    if fileExists
+      then return (Just filePath)
+      else findDefFile roots name
+
+-- Finds the innermost root that contains a file
+findRoot :: Roots -> FilePath -> Maybe (String, FilePath)
+findRoot roots filePath = case [root | root@(_, bookPath) <- roots, (bookPath ++ "/") `isPrefixOf` filePath] of
+  []    -> Nothing
+  found -> Just (maximumBy (comparing (length . snd)) found)
+
+-- Puts a name under a mount prefix
+mountName :: String -> String -> String
+mountName ""     name = name
+mountName prefix name = prefix ++ "/" ++ name
+
+-- Takes a name out of a mount prefix, if it is under it
+unmountName :: String -> String -> Maybe String
+unmountName ""     name = Just name
+unmountName prefix name
+  | (prefix ++ "/") `isPrefixOf` name = Just (drop (length prefix + 1) name)
+  | otherwise                         = Nothing
+
+-- Moves the definitions of a file from a mounted dependency under its prefix,
+-- along with its references to the names that the dependency itself defines
+mountBook :: Roots -> FilePath -> Book -> IO Book
+mountBook roots filePath book = case findRoot roots filePath of
+  Just (prefix, _) | not (null prefix) -> do
+    let depRoots = [(sub, bookPath) | (root, bookPath) <- roots, Just sub <- [unmountRoot prefix root]]
+    let names    = nub (M.keys book ++ concatMap getDeps (M.elems book))
+    owned <- filterM (\nam -> if M.member nam book then return True else isJust <$> findDefFile depRoots nam) names
+    let owned' = S.fromList owned
+    let rename nam = if S.member nam owned' then mountName prefix nam else nam
+    return $ M.fromList [(rename nam, renameRefs rename term) | (nam, term) <- M.toList book]
+  _ -> return book
+  where
+    unmountRoot prefix root
+      | root == prefix = Just ""
+      | otherwise      = unmountName prefix root
+
+-- Adds the eliminators and folds of a file's `data` declarations, except
+-- those that are defined by hand (in the file, or in their own)
+addElims :: Roots -> Book -> IO Book
+addElims roots book = do
+  elims <- filterM (\ (nam, _) -> not . isJust <$> findDefFile roots nam) (M.toList (bookElims book M.\\ book))
+  return $ M.union book (M.fromList elims)
+
+-- The definitions of a file to check: its own, and the eliminators and folds
+-- generated for its `data` declarations, as these are trusted where used
+fileChecks :: FileCtx -> FilePath -> [String]
+fileChecks (book, defs, _, _) file = names ++ [nam | nam <- M.keys (bookElims own), M.member nam book, not (S.member nam owned)] where
+  names = M.findWithDefault [] file defs
+  own   = M.restrictKeys book (S.fromList names)
+  owned = S.fromList (concat (M.elems defs))
+
+-- Adds the definitions of a file's `deriving` clauses, given the declarations
+-- of their fields' types; an error is reported where its clause ends
+addDerived :: (String -> Maybe Term) -> Book -> M.Map String Deriving -> Either P.ParseError Book
+addDerived decl book clauses = do
+  derived <- forM (M.toList clauses) $ \ (name, (pos, classes)) ->
+    either (\ err -> Left (newErrorMessage (Expect err) pos)) Right (deriveDefs decl name classes (book M.! name))
+  return $ M.union book (M.fromList (concat derived))
+
+-- Loads a file and all its dependencies recursivelly
+loadFile :: Roots -> Book -> FilePath -> IO (FileCtx, [ParseFail])
+loadFile roots book filePath = do
+  fileExists <- doesFileExist filePath
+  if not fileExists
+    then do
+      return ((book, M.empty, M.empty, S.empty), [])
+    else do
+      code <- readFile filePath
+      loadCode roots book filePath code
+
+-- Loads a file's source and all its dependencies recursivelly. The deriving
+-- clauses look into the declarations of their fields' types, so what the file
+-- refers to is loaded first; then the derived definitions and the generated
+-- eliminators are added, and what they refer to is loaded too
+loadCode :: Roots -> Book -> FilePath -> String -> IO (FileCtx, [ParseFail])
+loadCode roots book filePath code = case runParseBook filePath code of
+  Left err -> do
+    return ((book, M.singleton filePath [], M.singleton filePath [], S.empty), [(filePath, code, err)])
+  Right (parsed, partials, clauses) -> do
+    let prefix = maybe "" fst (findRoot roots filePath)
+    mounted <- mountBook roots filePath parsed
+    ((book1, defs1, deps1, part1), fails1) <- loadNames roots (M.union book mounted, M.empty, M.empty, S.empty) (concatMap getDeps (M.elems mounted))
+    let decl nam = M.lookup (mountName prefix nam) book1 <|> M.lookup nam book1
+    let (derived, fails2) = case addDerived decl parsed clauses of
+          Left err    -> (parsed, [(filePath, code, err)])
+          Right book' -> (book', [])
+    -- The generated eliminators aren't the file's own definitions, as they
+    -- have no source to check, document or rename
+    own   <- mountBook roots filePath derived
+    book0 <- addElims roots own
+    let deps  = concatMap getDeps (M.elems book0)
+    let defs' = M.insert filePath (M.keys own) defs1
+    let deps' = M.insert filePath deps deps1
+    let part' = S.union part1 (S.map (mountName prefix) partials)
+    (ctx, fails3) <- loadNames roots (M.union book0 book1, defs', deps', part') deps
+    return (ctx, fails1 ++ fails2 ++ fails3)
+
+-- Loads names and all their dependencies recursivelly into a context
+loadNames :: Roots -> FileCtx -> [String] -> IO (FileCtx, [ParseFail])
+loadNames roots ctx names = foldM (\ ((book, defs, deps, part), fails) name -> do
+    ((book', defs', deps', part'), fails') <- loadName roots book name
+    return ((book', M.union defs defs', M.union deps deps', S.union part part'), fails ++ fails')
+  ) (ctx, []) names
+
+-- Loads every file in the main book root
+loadBook :: Roots -> IO (FileCtx, [ParseFail])
+loadBook roots = do
+  files <- findKindFiles (snd (head roots))
+  foldM (\ ((book, defs, deps, part), fails) file -> do
+      ((book', defs', deps', part'), fails') <- loadFile roots book file
+      return ((book', M.union defs defs', M.union deps deps', S.union part part'), fails ++ fails')
+    ) ((M.empty, M.empty, M.empty, S.empty), []) files
+
+-- Finds the files with definitions that depend on the given file's, directly or indirectly
+getFileRDeps :: FileCtx -> FilePath -> [FilePath]
+getFileRDeps (book, defs, _, _) file =
+  let names = S.fromList (M.findWithDefault [] file defs)
+  in [other | (other, otherNames) <- M.toList defs, other /= file, any (\nam -> not (S.disjoint names (getAllDeps book nam))) otherNames]
+
+-- Finds all Kind files in this directory tree
+findKindFiles :: FilePath -> IO [FilePath]
+findKindFiles dir = do
+  contents <- getDirectoryContents dir
+  let properNames = filter (`notElem` [".", ".."]) contents
+  paths <- forM properNames $ \name -> do
+    let path = dir </> name
+    isDirectory <- doesDirectoryExist path
+    if isDirectory
+      then findKindFiles path
+      else return [path | ".kind" `isSuffixOf` path]
+  return (concat paths)
@@ -0,0 +1,138 @@
+-- //./Type.hs//
+
+-- Project manifest (`kind.toml`). Declares the package name, its source root
+-- and the local books it depends on, each mounted under a name prefix:
+--
+--   [package]
+--   name = "app"
+--   root = "kindbook"
+--
+--   [dependencies]
+--   Base = { path = "../base" }
+--
+-- With this, `Base/Nat/add` resolves to `../base/kindbook/Nat/add.kind` (or to
+-- `../base/Nat/add.kind`, when `../base` has no manifest of its own).
+
+module Kind.Manifest where
+
+import Control.Applicative ((<|>))
+import Control.Monad (forM)
+import Data.Maybe (fromMaybe)
+import Kind.JSON
+import Kind.Load
+import System.Directory (canonicalizePath, doesDirectoryExist, doesFileExist)
+import System.FilePath (takeDirectory, (</>))
+import System.IO (readFile')
+import qualified Text.Parsec as P
+
+-- Package name, source root and dependencies (mount prefix, path)
+data Manifest = Manifest String FilePath [(String, FilePath)]
+
+-- Loading
+-- -------
+
+-- Finds the nearest manifest, searching upwards
+findManifest :: FilePath -> IO (Maybe FilePath)
+findManifest dir = do
+  let manifestPath = dir </> "kind.toml"
+  foundManifest <- doesFileExist manifestPath
+  if foundManifest
+    then return $ Just manifestPath
+    else if takeDirectory dir == dir
+      then return Nothing
+      else findManifest (takeDirectory dir)
+
+-- Gets the roots declared by a manifest: the package's own, then its
+-- dependencies', recursively, with nested prefixes. A declared root that
+-- doesn't exist is an error.
+loadManifestRoots :: FilePath -> IO (Either String Roots)
+loadManifestRoots manifestPath = go [] "" manifestPath where
+  go seen prefix manifestPath = do
+    dir  <- canonicalizePath (takeDirectory manifestPath)
+    code <- readFile' manifestPath
+    case parseManifest code of
+      Left err -> return $ Left $ "Error: Invalid manifest '" ++ manifestPath ++ "': " ++ err
+      Right _ | dir `elem` seen -> return $ Left $ "Error: Dependency cycle through '" ++ manifestPath ++ "'."
+      Right (Manifest _ root deps) -> do
+        hasRoot <- doesDirectoryExist (dir </> root)
+        depRoots <- forM deps $ \ (dep, depPath) -> do
+          let depDir = dir </> depPath
+          hasDir      <- doesDirectoryExist depDir
+          hasManifest <- doesFileExist (depDir </> "kind.toml")
+          if not hasDir
+            then return $ Left $ "Error: The path '" ++ depPath ++ "' of dependency '" ++ dep ++ "' in '" ++ manifestPath ++ "' doesn't exist."
+            else if hasManifest
+              then go (dir : seen) (mountName prefix dep) (depDir </> "kind.toml")
+              else return $ Right [(mountName prefix dep, depDir)]
+        return $ if not hasRoot
+          then Left $ "Error: The root '" ++ root ++ "' declared in '" ++ manifestPath ++ "' doesn't exist."
+          else ((prefix, dir </> root) :) . concat <$> sequence depRoots
+
+-- Parsing
+-- -------
+
+-- Parses a manifest. Only the subset of TOML used by manifests is supported:
+-- tables, and keys set to strings or to inline tables of strings.
+parseManifest :: String -> Either String Manifest
+parseManifest code = case P.parse (skipTrivia *> parseTables <* P.eof) "kind.toml" code of
+  Left err     -> Left (show err)
+  Right tables -> do
+    let get tab key = lookup tab tables >>= lookup key >>= jsonStr
+    deps <- forM (fromMaybe [] (lookup "dependencies" tables)) $ \ (dep, val) ->
+      case jsonStr val <|> (jsonGet ["path"] val >>= jsonStr) of
+        Just path -> Right (dep, path)
+        Nothing   -> Left $ "dependency '" ++ dep ++ "' has no path"
+    return $ Manifest (fromMaybe "" (get "package" "name")) (fromMaybe "kindbook" (get "package" "root")) deps
+
+type ManifestParser a = P.Parsec String () a
+
+parseTables :: ManifestParser [(String, [(String, JSON)])]
+parseTables = do
+  top  <- P.many parseEntry
+  tabs <- P.many $ do
+    P.char '['
+    name <- parseKey
+    P.char ']'
+    skipTrivia
+    entries <- P.many parseEntry
+    return (name, entries)
+  return (("", top) : tabs)
+
+parseEntry :: ManifestParser (String, JSON)
+parseEntry = do
+  key <- parseKey
+  skipSpaces
+  P.char '='
+  skipSpaces
+  val <- parseTomlValue
+  skipTrivia
+  return (key, val)
+
+parseKey :: ManifestParser String
+parseKey = parseString P.<|> P.many1 (P.alphaNum P.<|> P.oneOf "_-/.")
+
+parseTomlValue :: ManifestParser JSON
+parseTomlValue = P.choice
+  [ JStr <$> parseString
+  , do
+      P.char '{'
+      skipSpaces
+      fields <- P.sepBy (parseInlineField <* skipSpaces) (P.char ',' >> skipSpaces)
+      P.char '}'
+      return (JObj fields)
+  ]
+  where
+    parseInlineField = do
+      key <- parseKey
+      skipSpaces
+      P.char '='
+      skipSpaces
+      val <- parseTomlValue
+      return (key, val)
+
+skipSpaces :: ManifestParser ()
+skipSpaces = P.skipMany (P.oneOf " \t")
+
+-- Skips whitespace, line breaks and comments
+skipTrivia :: ManifestParser ()
+skipTrivia = P.skipMany (P.space P.<|> (P.char '#' >> P.skipMany (P.noneOf "\n") >> return ' '))
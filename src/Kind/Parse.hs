@@ -11,6 +11,7 @@ import Data.Word
 import Debug.Trace
 import Highlight (highlightError, highlight)
 import Kind.Equal
+import Kind.JSON
 import Kind.Reduce
 import Kind.Show
 import Kind.Type
@@ -21,6 +22,7 @@ import Text.Parsec.Error (errorPos, errorMessages, showErrorMessages, ParseError
 import qualified Control.Applicative as A
 import qualified Data.IntMap.Strict as IM
 import qualified Data.Map.Strict as M
+import qualified Data.Set as S
 import qualified Text.Parsec as P
 
 type Uses     = [(String, String)]
@@ -117,17 +119,22 @@ doParseUses filename input =
     Right uses -> return uses
 
 doParseBook :: String -> String -> IO Book
-doParseBook filename input = do
-  let parser = do
-        skip
-        uses <- parseUses
-        setState (filename, 0, uses)
-        parseBook <* P.eof
-  case P.runParser parser (filename, 0, []) filename input of
+doParseBook filename input =
+  case runParseBook filename input of
     Left err -> do
       showParseError filename input err
       return M.empty
-    Right book -> return book
+    Right (book, _, _) -> return book
+
+-- Parses a file's definitions, the names of those marked `#partial`, and the
+-- `deriving` clauses of its `data` declarations
+runParseBook :: String -> String -> Either P.ParseError (Book, S.Set String, M.Map String Deriving)
+runParseBook filename input = P.runParser parser (filename, 0, []) filename input where
+  parser = do
+    skip
+    uses <- parseUses
+    setState (filename, 0, uses)
+    parseBook <* P.eof
 
 -- Error handling
 extractExpectedTokens :: ParseError -> String
@@ -148,6 +155,19 @@ showParseError filename input err = do
   putStrLn $ setSGRCode [SetUnderlining SingleUnderline] ++ filename ++
              setSGRCode [Reset] ++ " " ++ show lin ++ ":" ++ show col
 
+showParseErrorJSON :: String -> P.ParseError -> IO ()
+showParseErrorJSON filename err = do
+  let pos = errorPos err
+  let lin = sourceLine pos
+  let col = sourceColumn pos
+  let cod = Cod (Loc filename lin col) (Loc filename lin (col + 1))
+  putStrLn $ showJSON $ JObj
+    [ ("kind", JStr "parse-error")
+    , ("file", JStr filename)
+    , ("range", codToJSON cod)
+    , ("expected", JArr [JStr msg | Expect msg <- errorMessages err, msg /= "Space", msg /= "Comment"])
+    ]
+
 -- Parsing helpers
 -- FIXME: currently, this will include suffix trivia. how can we avoid that?
 withSrc :: Parser Term -> Parser Term
@@ -192,6 +212,7 @@ parseTerm = do
     , (parseSwiInl,          discard $ string_skp "switch ")
     , (parseKVs,             discard $ string_skp "{")
     , (parseDo,              discard $ string_skp "do ")
+    , (parseUni,             discard $ char '*' >> digit)
     , (parseSet,             discard $ string_skp "*")
     , (parseFloat,           discard $ string_skp "-" <|> (P.many1 digit >> string_skp "."))
     , (parseNum,             discard $ numeric)
@@ -495,6 +516,12 @@ parseUse = parseLocal "use" Use
 
 parseSet = withSrc $ char '*' >> return Set
 
+-- A universe of a given level, like `*0`, for `--universes`
+parseUni = withSrc $ do
+  char '*'
+  lvl <- numeric
+  return $ Uni (Level (read lvl) [])
+
 parseFloat = withSrc $ P.try $ do
   -- Parse optional negative sign
   sign <- P.option id $ P.char '-' >> return negate
@@ -661,16 +688,27 @@ parseSuffVal term = return term
 -- Book Parser
 -- -----------
 
-parseBook :: Parser Book
-parseBook = M.fromList <$> P.many parseDef
+-- A `deriving` clause: where it ends (to report errors at) and its classes.
+-- The definitions it asks for are generated by the loader
+type Deriving = (P.SourcePos, [String])
 
-parseDef :: Parser (String, Term)
+parseBook :: Parser (Book, S.Set String, M.Map String Deriving)
+parseBook = do
+  defs <- P.many parseDef
+  return ( M.fromList [(nam, term) | (nam, term, _, _) <- defs]
+         , S.fromList [nam | (nam, _, True, _) <- defs]
+         , M.fromList [(nam, der) | (nam, _, _, Just der) <- defs] )
+
+-- Parses a top-level definition, with whether it is marked `#partial` and its
+-- `deriving` clause
+parseDef :: Parser (String, Term, Bool, Maybe Deriving)
 parseDef = guardChoice
-  [ (parseDefADT, discard $ string_skp "data ")
-  , (parseDefFun, discard $ string_skp "#" <|> name_skp)
+  [ ((\ (nam, term, der) -> (nam, term, False, der)) <$> parseDefADT, discard $ string_skp "data ")
+  , ((\ (nam, term, par) -> (nam, term, par, Nothing)) <$> parseDefFun, discard $ string_skp "#" <|> name_skp)
   ] $ fail "Top-level definition"
 
-parseDefADT :: Parser (String, Term)
+-- Parses a `data` declaration, along with its `deriving` clause
+parseDefADT :: Parser (String, Term, Maybe Deriving)
 parseDefADT = do
   (_, _, uses) <- P.getState
   P.try $ string_skp "data "
@@ -699,6 +737,13 @@ parseDefADT = do
   char_skp '{'
   ctrs <- P.many $ P.try parseADTCtr
   char_skp '}'
+  clause <- P.optionMaybe $ do
+    P.try $ string "deriving" >> P.notFollowedBy name_char >> skip
+    char_skp '('
+    classes <- P.sepBy name_skp (char_skp ',')
+    char_skp ')'
+    pos <- getPosition
+    return (pos, classes)
   let paramTypes = map snd params
   let indexTypes = map snd indices
   let paramNames = map fst params
@@ -712,14 +757,16 @@ parseDefADT = do
   let term       = bind (genMetas (Ann False fullBody typeBody)) []
   return $
     -- trace ("parsed " ++ nameA ++ " = " ++ (showTermGo False term 0))
-    (nameA, term)
+    (nameA, term, clause)
   where fillCtrRet  ret (Ctr nm tele)    = Ctr nm (fillTeleRet ret tele)
         fillTeleRet ret (TRet (Met _ _)) = TRet ret
         fillTeleRet _   (TRet ret)       = TRet ret
         fillTeleRet ret (TExt nm tm bod) = TExt nm tm (\x -> fillTeleRet ret (bod x)) -- FIXME: 'bod x'?
 
-parseDefFun :: Parser (String, Term)
+-- `#partial` exempts a definition from the termination check
+parseDefFun :: Parser (String, Term, Bool)
 parseDefFun = do
+  partial <- P.option False $ P.try $ string "#partial" >> P.notFollowedBy name_char >> skip >> return True
   numb <- P.optionMaybe $ char_skp '#'
   name <- name_skp
   typ <- P.optionMaybe $ do
@@ -735,8 +782,8 @@ parseDefFun = do
   let name1 = if isJust numb then name0 ++ "#" ++ show count else name0
   P.setState (filename, if isJust numb then count + 1 else count, uses)
   case typ of
-    Nothing -> return (name1, bind (genMetas val) [])
-    Just t  -> return (name1, bind (genMetas (Ann False val t)) [])
+    Nothing -> return (name1, bind (genMetas val) [], partial)
+    Just t  -> return (name1, bind (genMetas (Ann False val t)) [], partial)
 
 parseDefFunSingle :: Parser Term
 parseDefFunSingle = do
@@ -0,0 +1,62 @@
+-- //./Type.hs//
+
+-- Positivity checker. A `data` declaration is only consistent when the type
+-- being defined occurs strictly positively in the fields of its constructors:
+-- never to the left of an arrow, nor in the arguments of a variable or of
+-- itself. It may occur in the arguments of other definitions (like `List T`)
+-- when these, unfolded, only use it strictly positively too.
+
+module Kind.Positivity where
+
+import Data.Maybe (listToMaybe)
+import Kind.Type
+import Kind.Util
+import qualified Data.Map.Strict as M
+import qualified Data.Set as S
+
+-- Finds a constructor field where the datatype occurs non-positively, with
+-- the field's location
+positivity :: Book -> Term -> [Ctr] -> Int -> Maybe (Maybe Cod, Term)
+positivity book typ cts dep = case getSpine typ of
+  (Ref name, _) -> listToMaybe
+    [ (fieldSrc fld, fld)
+    | Ctr _ tele <- cts
+    , (_, fld) <- getTeleFields tele dep []
+    , not (positive book name S.empty fld dep) ]
+  _ -> Nothing
+  where
+    fieldSrc (Src cod _)   = Just cod
+    fieldSrc (Ann _ val _) = fieldSrc val
+    fieldSrc _             = Nothing
+
+-- Checks if a type only has strictly positive occurrences of a name. The
+-- definitions unfolded so far are kept, so that recursive ones (like `List`)
+-- are only unfolded once; past that, their arguments must be positive.
+positive :: Book -> String -> S.Set String -> Term -> Int -> Bool
+positive book name seen term dep
+  | not (occurs name term) = True
+  | otherwise = case term of
+    Src _ val       -> positive book name seen val dep
+    Ann _ val _     -> positive book name seen val dep
+    All nam inp bod -> not (occurs name inp) && positive book name seen (bod (Var nam dep)) (dep + 1)
+    _               -> case getSpine term of
+      (Ref nam, args)
+        | nam == name       -> not (any (occurs name) args)
+        | S.member nam seen -> all (\arg -> positive book name seen arg dep) args
+        | otherwise         -> case M.lookup nam book >>= \def -> unfold def args of
+          Just (ADT _ cts _) -> and [positive book name (S.insert nam seen) fld dep | Ctr _ tele <- cts, (_, fld) <- getTeleFields tele dep []]
+          Just body          -> positive book name (S.insert nam seen) body dep
+          Nothing            -> False
+      _ -> False
+
+-- Applies a definition to arguments, when it takes at least that many
+unfold :: Term -> [Term] -> Maybe Term
+unfold (Src _ val)   args         = unfold val args
+unfold (Ann _ val _) args         = unfold val args
+unfold (Lam _ bod)   (arg : args) = unfold (bod arg) args
+unfold (Lam _ _)     []           = Nothing
+unfold term          args         = Just (foldl App term args)
+
+-- Checks if a term refers to a name
+occurs :: String -> Term -> Bool
+occurs name term = name `elem` getDeps term
@@ -199,6 +199,7 @@ normal book fill lv term dep = go (reduce book fill lv term) dep where
     Use nam nf_val nf_bod
   go (Hol nam ctx) dep = Hol nam ctx
   go Set dep = Set
+  go (Uni lvl) dep = Uni lvl
   go U64 dep = U64
   go F64 dep = F64
   go (Num val) dep = Num val
@@ -327,6 +328,7 @@ bind (Use nam val bod) ctx =
   let bod' = \x -> bind (bod (Var nam 0)) ((nam, x) : ctx) in
   Use nam val' bod'
 bind Set ctx = Set
+bind (Uni lvl) ctx = Uni lvl
 bind U64 ctx = U64
 bind F64 ctx = F64
 bind (Num val) ctx = Num val
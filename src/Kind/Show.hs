@@ -82,6 +82,7 @@ showTermGo small term dep =
             bod' = showTermGo small (bod (Var nam dep)) (dep + 1)
         in concat ["use " , nam' , " = " , val' , " " , bod']
       Set -> "*"
+      Uni lvl -> concat ["*", showLevel lvl]
       U64 -> "U64"
       F64 -> "F64"
       Num val ->
@@ -136,6 +137,14 @@ showTermGo small term dep =
         then showTermGo small val dep
         else concat ["!", showTermGo small val dep]
 
+-- Shows a universe level: a number, a level variable (like `u3`, or `u3+1`
+-- with an offset), or the maximum of these
+showLevel :: Level -> String
+showLevel (Level c xs) = case atoms of
+  [atom] -> atom
+  _      -> concat ["(max ", unwords atoms, ")"]
+  where atoms = [show c | c > 0 || null xs] ++ ["u" ++ show v ++ (if k > 0 then "+" ++ show k else "") | (v, k) <- xs]
+
 -- CHANGED: Added showTeleGo function
 showTeleGo :: Bool -> Tele -> Int -> String
 showTeleGo small tele dep = "{ " ++ go tele dep where
@@ -0,0 +1,182 @@
+-- //./Type.hs//
+
+-- Termination checker. A definition terminates when its recursive calls are
+-- structurally decreasing: each call passes, for some order of the
+-- parameters, a lexicographically smaller tuple of arguments, where the
+-- fields bound by the `Mat`/`Swi` eliminations of a parameter (as produced by
+-- the equation flattener) are smaller than it. The fields of a constructor are
+-- those of the declared datatype of the scrutinee; eliminations of scrutinees
+-- of unknown datatypes give nothing smaller. Mutual recursion isn't
+-- analyzed, so definitions that call back through others are rejected.
+
+module Kind.Termination where
+
+import Control.Monad (join)
+import Data.Maybe (listToMaybe)
+import Kind.Type
+import Kind.Util
+import qualified Data.IntMap.Strict as IM
+import qualified Data.Map.Strict as M
+import qualified Data.Set as S
+
+-- Size of a variable: smaller than, or the same as, the parameter at a position
+data Size = Lt Int | Le Int
+
+-- What is known of an argument: its size, and the datatype of its type
+type Arg = (Maybe Size, Maybe String)
+
+-- Relation of an argument to the parameter at its position
+data Rel = Less | Same | Unknown deriving (Eq)
+
+-- A recursive call: its location, the call, and the sizes of its arguments
+type Call = (Maybe Cod, Term, [Maybe Size])
+
+-- Finds a recursive call of a definition that isn't known to decrease
+termination :: Book -> String -> Term -> Maybe (Maybe Cod, Term)
+termination book name term
+  | isType term    = Nothing
+  | decreases rels = Nothing
+  | otherwise      = Just (head ([(src, call) | ((src, call, _), rel) <- zip calls rels, all (== Unknown) rel] ++ [(src, call) | (src, call, _) <- calls]))
+  where
+    calls = termCalls book name term
+    rels  = [zipWith relate [0 ..] sizes | (_, _, sizes) <- calls]
+    relate pos (Just (Lt i)) | i == pos = Less
+    relate pos (Just (Le i)) | i == pos = Same
+    relate pos _                        = Unknown
+
+-- Checks if every call decreases lexicographically, for some order of the
+-- parameters: one of them never grows and shrinks in some calls, and the
+-- calls where it stays the same decrease on the others
+decreases :: [[Rel]] -> Bool
+decreases []   = True
+decreases rels = any try [0 .. maximum (map length rels) - 1] where
+  try pos = all ((/= Unknown) . at pos) rels
+         && any ((== Less) . at pos) rels
+         && decreases [rel | rel <- rels, at pos rel == Same]
+  at pos rel = if pos < length rel then rel !! pos else Unknown
+
+-- Collects the recursive calls of a definition, and its calls to definitions
+-- that call it back (which count as calls of unknown size)
+termCalls :: Book -> String -> Term -> [Call]
+termCalls book name term = go term 0 IM.empty (Just 0) Nothing where
+
+  -- Walks a term. On the spine of the definition (`Just n`, with n parameters
+  -- bound so far), lambdas and eliminations take the next parameter.
+  go :: Term -> Int -> IM.IntMap Arg -> Maybe Int -> Maybe Cod -> [Call]
+  go term dep vars spine src = case term of
+    Src cod val -> go val dep vars spine (Just cod)
+    Ann _ val _ -> go val dep vars spine src
+    Lam _ _     | Just n <- spine -> feed term dep vars [param n] (Just (n + 1)) src
+    Mat _       | Just n <- spine -> feed term dep vars [param n] (Just (n + 1)) src
+    Swi _ _     | Just n <- spine -> feed term dep vars [param n] (Just (n + 1)) src
+    Lam nam bod -> go (bod (Var nam dep)) (dep + 1) vars Nothing src
+    Mat cse     -> concat [go bod dep vars Nothing src | (_, bod) <- cse]
+    Swi zer suc -> go zer dep vars Nothing src ++ go suc dep vars Nothing src
+    App _ _     -> let (fun, args) = getSpine term in apply fun args dep vars spine src
+    Ref _       -> apply term [] dep vars spine src
+    All nam inp bod -> go inp dep vars Nothing src ++ go (bod (Var nam dep)) (dep + 1) vars Nothing src
+    Slf nam typ bod -> go typ dep vars Nothing src ++ go (bod (Var nam dep)) (dep + 1) vars Nothing src
+    Ins val     -> go val dep vars Nothing src
+    Con _ arg   -> concat [go val dep vars Nothing src | (_, val) <- arg]
+    Let nam val bod -> go val dep vars Nothing src ++ go (bod (Var nam dep)) (dep + 1) (bindArg dep (argOf vars val) vars) spine src
+    Use nam val bod -> go val dep vars Nothing src ++ go (bod val) dep vars spine src
+    Op2 _ fst snd -> go fst dep vars Nothing src ++ go snd dep vars Nothing src
+    KVs kvs def -> concatMap (\val -> go val dep vars Nothing src) (def : IM.elems kvs)
+    Get g n m k bod -> go m dep vars Nothing src ++ go k dep vars Nothing src ++ go (bod (Var g dep) (Var n (dep + 1))) (dep + 2) vars Nothing src
+    Put g n m k v bod -> go m dep vars Nothing src ++ go k dep vars Nothing src ++ go v dep vars Nothing src ++ go (bod (Var g dep) (Var n (dep + 1))) (dep + 2) vars Nothing src
+    Log msg nxt -> go msg dep vars Nothing src ++ go nxt dep vars spine src
+    Lst vals    -> concatMap (\val -> go val dep vars Nothing src) vals
+    _           -> []
+
+  -- Walks a function applied to the given arguments. Eliminations pass fields
+  -- smaller than their scrutinee on to their cases, when the scrutinee's
+  -- datatype (and so the number of fields) is known.
+  feed :: Term -> Int -> IM.IntMap Arg -> [Arg] -> Maybe Int -> Maybe Cod -> [Call]
+  feed term dep vars []           spine src = go term dep vars spine src
+  feed term dep vars (arg : args) spine src = case term of
+    Src cod val -> feed val dep vars (arg : args) spine (Just cod)
+    Lam nam bod -> feed (bod (Var nam dep)) (dep + 1) (bindArg dep arg vars) args spine src
+    Mat cse     -> concat
+      [ case (ctr, fieldsOf (snd arg) ctr) of
+          ("_", _)       -> feed bod dep vars (arg : args) spine src
+          (_, Just flds) -> feed bod dep vars ([(smaller (fst arg), fld) | fld <- flds] ++ args) spine src
+          (_, Nothing)   -> go bod dep vars Nothing src
+      | (ctr, bod) <- cse ]
+    Swi zer suc -> feed zer dep vars args spine src ++ feed suc dep vars ((smaller (fst arg), Nothing) : args) spine src
+    _           -> go term dep vars Nothing src
+
+  -- Walks an application: recursive calls are recorded with the sizes of
+  -- their arguments, and redexes of eliminations are fed their arguments
+  apply :: Term -> [Term] -> Int -> IM.IntMap Arg -> Maybe Int -> Maybe Cod -> [Call]
+  apply fun args dep vars spine src = here ++ concat [go arg dep vars Nothing src | arg <- args] where
+    here = case fun of
+      Ref nam | nam == name     -> [(src, foldl App fun args, map (fst . argOf vars) args)]
+      Ref nam | callsBack nam   -> [(src, foldl App fun args, [])]
+      Ref _                     -> []
+      Mat _                     -> feed fun dep vars (map (argOf vars) args) spine src
+      Swi _ _                   -> feed fun dep vars (map (argOf vars) args) spine src
+      Lam _ _                   -> feed fun dep vars (map (argOf vars) args) spine src
+      _                         -> go fun dep vars Nothing src
+
+  -- Definitions (other than types) that depend on this one
+  callsBack nam = case M.lookup nam book of
+    Just def -> not (isType def) && S.member name (getAllDeps book nam)
+    Nothing  -> False
+
+  -- The parameter at a position, with the datatype of its declared type
+  param n = (Just (Le n), join (listToMaybe (drop n params)))
+  params  = case unSrc term of
+    Ann _ _ typ -> paramTypes typ
+    _           -> []
+  paramTypes typ = case unSrc typ of
+    All nam inp bod -> typeName inp : paramTypes (bod (Var nam 0))
+    _               -> []
+
+  -- The datatypes of the fields of a constructor of a datatype
+  fieldsOf typ ctr = do
+    dat <- typ
+    listToMaybe [map (typeName . snd) (getTeleFields tele 0 []) | Ctr nam tele <- ctrsOf (8 :: Int) dat, nam == ctr]
+
+  -- The constructors of a datatype, seeing through aliases like `(List Char)`
+  ctrsOf fuel dat = case M.lookup dat book of
+    Just def | not (null (getDefCtrs def)) -> getDefCtrs def
+    Just def | fuel > 0, Just ali <- typeName (unAnn def) -> ctrsOf (fuel - 1) ali
+    _ -> []
+
+-- The datatype that a type is an application of
+typeName :: Term -> Maybe String
+typeName typ = case getSpine (unAnn typ) of
+  (Ref nam, _) -> Just nam
+  _            -> Nothing
+
+unSrc :: Term -> Term
+unSrc (Src _ val) = unSrc val
+unSrc term        = term
+
+unAnn :: Term -> Term
+unAnn (Src _ val)   = unAnn val
+unAnn (Ann _ val _) = unAnn val
+unAnn term          = term
+
+-- Checks if a definition is a `data` declaration
+isType :: Term -> Bool
+isType (Src _ val)   = isType val
+isType (Ann _ val _) = isType val
+isType (Lam nam bod) = isType (bod (Var nam 0))
+isType (ADT _ _ _)   = True
+isType _             = False
+
+-- What is known of an argument, when it is a variable bound under an elimination
+argOf :: IM.IntMap Arg -> Term -> Arg
+argOf vars (Var _ idx)   = IM.findWithDefault (Nothing, Nothing) idx vars
+argOf vars (Src _ val)   = argOf vars val
+argOf vars (Ann _ val _) = argOf vars val
+argOf vars _             = (Nothing, Nothing)
+
+bindArg :: Int -> Arg -> IM.IntMap Arg -> IM.IntMap Arg
+bindArg dep (Nothing, Nothing) vars = IM.delete dep vars
+bindArg dep arg                vars = IM.insert dep arg vars
+
+smaller :: Maybe Size -> Maybe Size
+smaller (Just (Le i)) = Just (Lt i)
+smaller size          = size
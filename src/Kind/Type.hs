@@ -48,6 +48,9 @@ data Term
   -- Universe: `Set`
   | Set
 
+  -- Leveled Universe: `Set 0`, `Set 1`, ...
+  | Uni Level
+
   -- U64 Type: `U64`
   | U64
 
@@ -136,26 +139,34 @@ data Tele
 -- Constructor
 data Ctr = Ctr String Tele
 
+-- Universe Level: the maximum of a constant and of level variables plus offsets
+data Level = Level Int [(Int, Int)] deriving (Eq)
+
+-- Level Constraint: a level is at most another
+data Bound = Bound Level Level
+
 -- Book of Definitions
 type Book = M.Map String Term
 
 -- Type-Checker Outputs
 data Info
-  = Found String Term [Term] Int
+  = Found (Maybe Cod) String Term [Term] Int
   | Solve Int Term Int
   | Error (Maybe Cod) Term Term Term Int
+  | Warning (Maybe Cod) String Term Int
   | Vague String
   | Print Term Int
 
 -- Unification Solutions
 type Fill = IM.IntMap Term
 
+-- Inferred Types of Top-Level References
+type Memo = M.Map String Term
+
 -- Checker State
 data Check = Check (Maybe Cod) Term Term Int -- postponed check
-data State = State Book Fill [Check] [Info] -- state type
+data Stats = Stats !Int !Int !Int !Int -- equal calls, unify calls, reductions, postponed checks
+data Event = Enter String [String] Int | Leave String -- trace event: a step (with its terms and depth) starts, or ends
+data State = State Book Fill [Check] [Info] Memo Stats (Maybe [Event]) (Maybe [Bound]) -- state type
 data Res a = Done State a | Fail State -- result type
 data Env a = Env (State -> Res a) -- monadic checker
-
--- UNCOMMENT THIS TO DEBUG THE TYPE CHECKER
--- debug a b = trace a b
-debug a b = b
@@ -0,0 +1,107 @@
+-- //./Type.hs//
+
+-- Universe levels. By default, `*` has type `*`, which is inconsistent. With
+-- `--universes`, each `*` of the book stands for `*u`, for a fresh level
+-- variable `u`; a universe `*l` has type `*(l+1)`, a function type
+-- lives in the maximum of its input's and output's universes, and a type is
+-- in every universe above its own (see `subsume`). The checker records these
+-- as bounds between levels, which must then have a solution.
+
+module Kind.Universe where
+
+import Data.List (mapAccumL)
+import Data.Maybe (listToMaybe)
+import Kind.Reduce
+import Kind.Type
+import qualified Data.IntMap.Strict as IM
+import qualified Data.Map.Strict as M
+import qualified Data.Set as S
+
+-- Levels
+-- ------
+
+levelSuc :: Level -> Level
+levelSuc (Level c xs) = Level (c + 1) [(v, k + 1) | (v, k) <- xs]
+
+levelMax :: Level -> Level -> Level
+levelMax (Level c xs) (Level d ys) = Level (max c d) (M.toList (M.fromListWith max (xs ++ ys)))
+
+-- Elaboration
+-- -----------
+
+-- Replaces each `*` of the book by a universe of a fresh level variable
+bookLevels :: Book -> Book
+bookLevels book = M.fromList $ snd $ mapAccumL elab 0 (M.toList book) where
+  elab c (nam, term) = let (term', c') = levelsGo term c in (c', (nam, bind term' []))
+
+-- Numbers the `*`s of a term from a counter, leaving the binders as
+-- placeholders (like `genMetasGo`), to be re-bound with `bind`
+levelsGo :: Term -> Int -> (Term, Int)
+levelsGo term c = case term of
+  Set               -> (Uni (Level 0 [(c, 0)]), c + 1)
+  All nam inp bod   -> let (inp', c1) = levelsGo inp c ; (bod', c2) = levelsGo (bod (Var nam 0)) c1 in (All nam inp' (\_ -> bod'), c2)
+  Lam nam bod       -> let (bod', c1) = levelsGo (bod (Var nam 0)) c in (Lam nam (\_ -> bod'), c1)
+  App fun arg       -> let (fun', c1) = levelsGo fun c ; (arg', c2) = levelsGo arg c1 in (App fun' arg', c2)
+  Ann chk val typ   -> let (val', c1) = levelsGo val c ; (typ', c2) = levelsGo typ c1 in (Ann chk val' typ', c2)
+  Slf nam typ bod   -> let (typ', c1) = levelsGo typ c ; (bod', c2) = levelsGo (bod (Var nam 0)) c1 in (Slf nam typ' (\_ -> bod'), c2)
+  Ins val           -> let (val', c1) = levelsGo val c in (Ins val', c1)
+  ADT scp cts typ   -> let (scp', c1) = many scp c ; (c2, cts') = mapAccumL ctr c1 cts ; (typ', c3) = levelsGo typ c2 in (ADT scp' cts' typ', c3)
+  Con nam arg       -> let (vals, c1) = many (map snd arg) c in (Con nam (zip (map fst arg) vals), c1)
+  Mat cse           -> let (bods, c1) = many (map snd cse) c in (Mat (zip (map fst cse) bods), c1)
+  Swi zer suc       -> let (zer', c1) = levelsGo zer c ; (suc', c2) = levelsGo suc c1 in (Swi zer' suc', c2)
+  Map typ           -> let (typ', c1) = levelsGo typ c in (Map typ', c1)
+  KVs kvs def       -> let (def', c1) = levelsGo def c ; (vals, c2) = many (IM.elems kvs) c1 in (KVs (IM.fromList (zip (IM.keys kvs) vals)) def', c2)
+  Get g n m k b     -> let ([m', k', b'], c1) = many [m, k, b (Var g 0) (Var n 0)] c in (Get g n m' k' (\_ _ -> b'), c1)
+  Put g n m k v b   -> let ([m', k', v', b'], c1) = many [m, k, v, b (Var g 0) (Var n 0)] c in (Put g n m' k' v' (\_ _ -> b'), c1)
+  Let nam val bod   -> let (val', c1) = levelsGo val c ; (bod', c2) = levelsGo (bod (Var nam 0)) c1 in (Let nam val' (\_ -> bod'), c2)
+  Use nam val bod   -> let (val', c1) = levelsGo val c ; (bod', c2) = levelsGo (bod (Var nam 0)) c1 in (Use nam val' (\_ -> bod'), c2)
+  Op2 opr fst snd   -> let (fst', c1) = levelsGo fst c ; (snd', c2) = levelsGo snd c1 in (Op2 opr fst' snd', c2)
+  Lst lst           -> let (lst', c1) = many lst c in (Lst lst', c1)
+  Log msg nxt       -> let (msg', c1) = levelsGo msg c ; (nxt', c2) = levelsGo nxt c1 in (Log msg' nxt', c2)
+  Src src val       -> let (val', c1) = levelsGo val c in (Src src val', c1)
+  _                 -> (term, c)
+  where
+    many terms c = let (c', terms') = mapAccumL (\c term -> let (term', c') = levelsGo term c in (c', term')) c terms in (terms', c')
+    ctr c (Ctr nam tele) = let (tele', c') = tele_ tele c in (c', Ctr nam tele')
+    tele_ (TRet term) c = let (term', c1) = levelsGo term c in (TRet term', c1)
+    tele_ (TExt nam typ bod) c = let (typ', c1) = levelsGo typ c ; (bod', c2) = tele_ (bod (Var nam 0)) c1 in (TExt nam typ' (\_ -> bod'), c2)
+
+-- Solving
+-- -------
+
+-- Finds a bound (with the definition that recorded it) that the level
+-- variables can't satisfy. Each bound is split into atoms `a ≤ l`, where `a`
+-- is a variable plus an offset, or a constant, and `l` is the whole upper
+-- level. An atom whose upper level is a maximum holds when any of its parts is
+-- high enough, so the search tries each part in turn, for the atoms that the
+-- least solution of the parts chosen so far doesn't already satisfy. With one
+-- part per atom, the least solution starts from 0 and grows the variables
+-- until every atom holds; a cycle that keeps increasing them runs out of fuel.
+solveLevels :: [(String, Bound)] -> Maybe (String, Bound)
+solveLevels bounds = either Just (const Nothing) (search [atom | atom@(_, _, _, [_]) <- atoms]) where
+  atoms = [(nam, bnd, lo, parts hi) | (nam, bnd@(Bound (Level c xs) hi)) <- bounds, lo <- (Nothing, c) : [(Just v, k) | (v, k) <- xs]]
+  vars  = S.fromList [v | (_, Bound (Level _ xs) (Level _ ys)) <- bounds, (v, _) <- xs ++ ys]
+
+  parts (Level d ys) = (Nothing, d) : [(Just w, m) | (w, m) <- ys]
+
+  value vals (Just v, k)  = IM.findWithDefault 0 v vals + k
+  value vals (Nothing, k) = k
+
+  -- Picks a part for the first atom that the least solution doesn't satisfy
+  search chosen = do
+    vals <- least chosen
+    case [atom | atom@(_, _, lo, alts) <- atoms, all (\ part -> value vals lo > value vals part) alts] of
+      [] -> Right vals
+      (nam, bnd, lo, alts) : _ -> firstRight [search ((nam, bnd, lo, [part]) : chosen) | part <- alts]
+
+  firstRight tries = case [vals | Right vals <- tries] of
+    vals : _ -> Right vals
+    []       -> head tries
+
+  -- The least solution of atoms with a single part
+  least chosen = go (S.size vars + 1) IM.empty where
+    go fuel vals = case [(nam, bnd, w, value vals lo - m) | (nam, bnd, lo, [(Just w, m)]) <- chosen, value vals lo - m > value vals (Just w, 0)] of
+      [] -> maybe (Right vals) Left $ listToMaybe [(nam, bnd) | (nam, bnd, lo, [part@(Nothing, _)]) <- chosen, value vals lo > value vals part]
+      grows@((nam, bnd, _, _) : _)
+        | fuel == 0 -> Left (nam, bnd)
+        | otherwise -> go (fuel - 1) (foldl (\ vals (_, _, w, need) -> IM.insertWith max w need vals) vals grows)
@@ -5,9 +5,14 @@ module Kind.Util where
 import Kind.Show
 import Kind.Type
 import Kind.Equal
+import Kind.Reduce
 
 import Prelude hiding (LT, GT, EQ)
 
+import Data.Bits (xor)
+import Data.Char (ord)
+import Data.Word (Word64)
+
 import qualified Data.IntMap.Strict as IM
 import qualified Data.Map.Strict as M
 import qualified Data.Set as S
@@ -41,6 +46,7 @@ getDeps term = case term of
   Log msg nxt     -> getDeps msg ++ getDeps nxt
   Var _ _         -> []
   Set             -> []
+  Uni _           -> []
   U64             -> []
   F64             -> []
   Num _           -> []
@@ -58,6 +64,77 @@ getDepsTele :: Tele -> [String]
 getDepsTele (TRet term) = getDeps term
 getDepsTele (TExt _ typ bod) = getDeps typ ++ getDepsTele (bod Set)
 
+-- Renames the top-level references of a term. Bodies are renamed with their
+-- variables left as placeholders, then re-bound, so that the renaming never
+-- reaches the values substituted into them later.
+renameRefs :: (String -> String) -> Term -> Term
+renameRefs f term = bind (go term) [] where
+  go term = case term of
+    Ref nam         -> Ref (f nam)
+    All nam inp bod -> All nam (go inp) (\_ -> go (bod (Var nam 0)))
+    Lam nam bod     -> Lam nam (\_ -> go (bod (Var nam 0)))
+    App fun arg     -> App (go fun) (go arg)
+    Ann chk val typ -> Ann chk (go val) (go typ)
+    Slf nam typ bod -> Slf nam (go typ) (\_ -> go (bod (Var nam 0)))
+    Ins val         -> Ins (go val)
+    ADT scp cts typ -> ADT (map go scp) (map goCtr cts) (go typ)
+    Con nam arg     -> Con nam [(fld, go val) | (fld, val) <- arg]
+    Mat cse         -> Mat [(cnam, go cbod) | (cnam, cbod) <- cse]
+    Let nam val bod -> Let nam (go val) (\_ -> go (bod (Var nam 0)))
+    Use nam val bod -> Use nam (go val) (\_ -> go (bod (Var nam 0)))
+    Op2 opr fst snd -> Op2 opr (go fst) (go snd)
+    Swi zer suc     -> Swi (go zer) (go suc)
+    Map typ         -> Map (go typ)
+    KVs kvs def     -> KVs (IM.map go kvs) (go def)
+    Get g n m k b   -> Get g n (go m) (go k) (\_ _ -> go (b (Var g 0) (Var n 0)))
+    Put g n m k v b -> Put g n (go m) (go k) (go v) (\_ _ -> go (b (Var g 0) (Var n 0)))
+    Src src val     -> Src src (go val)
+    Hol nam ctx     -> Hol nam (map go ctx)
+    Met uid spn     -> Met uid (map go spn)
+    Log msg nxt     -> Log (go msg) (go nxt)
+    Lst lst         -> Lst (map go lst)
+    _               -> term
+  goCtr (Ctr nam tele)       = Ctr nam (goTele tele)
+  goTele (TRet term)         = TRet (go term)
+  goTele (TExt nam typ bod)  = TExt nam (go typ) (\_ -> goTele (bod (Var nam 0)))
+
+-- Gets all source-located subterms of a term, with their depths
+getSrcs :: Term -> Int -> [(Cod, Term, Int)]
+getSrcs term dep = case term of
+  Src cod val     -> (cod, val, dep) : getSrcs val dep
+  All nam inp bod -> getSrcs inp dep ++ getSrcs (bod (Var nam dep)) (dep + 1)
+  Lam nam bod     -> getSrcs (bod (Var nam dep)) (dep + 1)
+  App fun arg     -> getSrcs fun dep ++ getSrcs arg dep
+  Ann _ val typ   -> getSrcs val dep ++ getSrcs typ dep
+  Slf nam typ bod -> getSrcs typ dep ++ getSrcs (bod (Var nam dep)) (dep + 1)
+  Ins val         -> getSrcs val dep
+  ADT scp cts t   -> concatMap (\x -> getSrcs x dep) scp ++ concatMap (\ (Ctr _ tele) -> getSrcsTele tele dep) cts ++ getSrcs t dep
+  Con _ arg       -> concatMap (\ (_, x) -> getSrcs x dep) arg
+  Mat cse         -> concatMap (\ (_, x) -> getSrcs x dep) cse
+  Let nam val bod -> getSrcs val dep ++ getSrcs (bod (Var nam dep)) (dep + 1)
+  Use nam val bod -> getSrcs val dep ++ getSrcs (bod (Var nam dep)) (dep + 1)
+  Op2 _ fst snd   -> getSrcs fst dep ++ getSrcs snd dep
+  Swi zer suc     -> getSrcs zer dep ++ getSrcs suc dep
+  Map val         -> getSrcs val dep
+  KVs kvs def     -> concatMap (\x -> getSrcs x dep) (IM.elems kvs) ++ getSrcs def dep
+  Get g n m k b   -> getSrcs m dep ++ getSrcs k dep ++ getSrcs (b (Var g dep) (Var n dep)) (dep + 2)
+  Put g n m k v b -> getSrcs m dep ++ getSrcs k dep ++ getSrcs v dep ++ getSrcs (b (Var g dep) (Var n dep)) (dep + 2)
+  Hol _ args      -> concatMap (\x -> getSrcs x dep) args
+  Met _ args      -> concatMap (\x -> getSrcs x dep) args
+  Log msg nxt     -> getSrcs msg dep ++ getSrcs nxt dep
+  Lst elems       -> concatMap (\x -> getSrcs x dep) elems
+  Sub val         -> getSrcs val dep
+  _               -> []
+
+-- Gets all source-located subterms of a telescope
+getSrcsTele :: Tele -> Int -> [(Cod, Term, Int)]
+getSrcsTele (TRet term)        dep = getSrcs term dep
+getSrcsTele (TExt nam typ bod) dep = getSrcs typ dep ++ getSrcsTele (bod (Var nam dep)) (dep + 1)
+
+-- Checks if a source location contains a (line, column) position
+codHas :: Cod -> (Int, Int) -> Bool
+codHas (Cod (Loc _ iniLin iniCol) (Loc _ endLin endCol)) pos = (iniLin, iniCol) <= pos && pos < (endLin, endCol)
+
 -- Gets all dependencies (direct and indirect) of a term
 getAllDeps :: Book -> String -> S.Set String
 getAllDeps book name = go S.empty [name] where
@@ -68,6 +145,17 @@ getAllDeps book name = go S.empty [name] where
         Just term -> go (S.insert x visited) (getDeps term ++ xs)
         Nothing   -> go (S.insert x visited) xs
 
+-- Hashes a string (64-bit FNV-1a)
+hashString :: String -> Word64
+hashString = foldl' (\ hash chr -> (hash `xor` fromIntegral (ord chr)) * 1099511628211) 14695981039346656037
+
+-- Hashes a definition together with all its dependencies (direct and indirect)
+getDefHash :: Book -> String -> Word64
+getDefHash book name = hashString $ concatMap go (S.toList (getAllDeps book name)) where
+  go dep = case M.lookup dep book of
+    Just term -> dep ++ " = " ++ showTermGo False term 0 ++ "\n"
+    Nothing   -> dep ++ " = ?\n"
+
 -- Topologically sorts a book
 topoSortBook :: Book -> [(String, Term)]
 topoSortBook book = go (M.keysSet book) [] where
@@ -141,6 +229,21 @@ getADTCts (ADT _ cts _) = map (\ ctr -> (getCtrName ctr, ctr)) cts
 getADTCts (Src loc val) = getADTCts val
 getADTCts term          = error ("not-an-adt:" ++ showTerm term)
 
+-- Splits an application into its head and arguments
+getSpine :: Term -> (Term, [Term])
+getSpine term = go term [] where
+  go (App fun arg) args = go fun (arg : args)
+  go (Src _ fun)   args = go fun args
+  go fun           args = (fun, args)
+
+-- Gets the constructors of a `data` declaration, or none for other definitions
+getDefCtrs :: Term -> [Ctr]
+getDefCtrs (Src _ val)   = getDefCtrs val
+getDefCtrs (Ann _ val _) = getDefCtrs val
+getDefCtrs (Lam nam bod) = getDefCtrs (bod (Var nam 0))
+getDefCtrs (ADT _ cts _) = cts
+getDefCtrs _             = []
+
 -- Given a typed term, return its argument's names
 getArgNames :: Term -> [String]
 getArgNames (Ann _ _ typ) = getForallNames typ